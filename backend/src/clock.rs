@@ -0,0 +1,194 @@
+use crate::rgp::{RgpAsicInfo, RgpClockCalibration, RgpCpuInfo};
+
+/// Drivers record CPU timestamps in nanoseconds unless the capture says otherwise.
+const DEFAULT_CPU_TIMESTAMP_FREQUENCY: u64 = NS_PER_SECOND;
+
+const NS_PER_SECOND: u64 = 1_000_000_000;
+
+/// A point in time known on both the SQTT clock and the GPU timestamp clock, e.g. the start of a command buffer whose
+/// CbStart marker and submission were both recorded.
+#[derive(Clone, Copy, Debug)]
+pub struct SqttAnchor {
+    pub sqtt_timestamp: u64,
+    pub gpu_timestamp: u64,
+}
+
+/// Converts timestamps between the clock domains of a capture.
+///
+/// SQTT packets count shader core clocks from the start of the trace, GPU timestamps (e.g. in queue events) tick at
+/// `gpu_timestamp_frequency`, and CPU timestamps use the host timebase. The clock calibration relates GPU timestamps
+/// to CPU timestamps, and an `SqttAnchor` relates the SQTT clock to GPU timestamps. Without both, SQTT times can only
+/// be given relative to the start of the trace.
+pub struct Timebase {
+    shader_clock_frequency: u64,
+    gpu_timestamp_frequency: u64,
+    cpu_timestamp_frequency: u64,
+    calibration: Option<RgpClockCalibration>,
+    sqtt_anchor: Option<SqttAnchor>,
+}
+
+impl Timebase {
+    pub fn new(
+        asic_info: &RgpAsicInfo,
+        cpu_info: Option<&RgpCpuInfo>,
+        calibration: Option<RgpClockCalibration>,
+        sqtt_anchor: Option<SqttAnchor>,
+    ) -> Self {
+        Timebase {
+            shader_clock_frequency: asic_info.trace_shader_core_clock,
+            gpu_timestamp_frequency: asic_info.gpu_timestamp_frequency,
+            cpu_timestamp_frequency: cpu_info
+                .map(|info| info.cpu_timestamp_freq)
+                .filter(|&frequency| frequency != 0)
+                .unwrap_or(DEFAULT_CPU_TIMESTAMP_FREQUENCY),
+            calibration,
+            sqtt_anchor,
+        }
+    }
+
+    /// Nanoseconds since the start of the trace.
+    pub fn sqtt_to_ns(&self, ticks: u64) -> u64 {
+        scale(ticks, NS_PER_SECOND, self.shader_clock_frequency)
+    }
+
+    /// Whether `sqtt_to_cpu_ns` returns times on the CPU clock.
+    pub fn sqtt_on_cpu_clock(&self) -> bool {
+        self.sqtt_anchor_cpu_ns().is_some()
+    }
+
+    /// The SQTT timestamp of the anchor and its time in nanoseconds on the CPU clock.
+    fn sqtt_anchor_cpu_ns(&self) -> Option<(u64, u64)> {
+        let anchor = self.sqtt_anchor?;
+        Some((anchor.sqtt_timestamp, self.gpu_to_cpu_ns(anchor.gpu_timestamp)?))
+    }
+
+    /// Nanoseconds on the CPU clock, or since the start of the trace if the SQTT clock can't be related to it (see
+    /// `sqtt_on_cpu_clock`).
+    pub fn sqtt_to_cpu_ns(&self, ticks: u64) -> u64 {
+        match self.sqtt_anchor_cpu_ns() {
+            Some((anchor_ticks, anchor_ns)) => {
+                let delta = ticks as i128 - anchor_ticks as i128;
+                let delta_ns = (delta * NS_PER_SECOND as i128).div_euclid(self.shader_clock_frequency.max(1) as i128);
+                (anchor_ns as i128 + delta_ns).clamp(0, u64::MAX as i128) as u64
+            }
+            None => self.sqtt_to_ns(ticks),
+        }
+    }
+
+    /// The inverse of `sqtt_to_cpu_ns`: the first SQTT timestamp at or after `ns`, clamped to the start of the trace.
+    pub fn cpu_ns_to_sqtt(&self, ns: u64) -> u64 {
+        let (anchor_ticks, anchor_ns) = self.sqtt_anchor_cpu_ns().unwrap_or((0, 0));
+        let delta_ns = ns as i128 - anchor_ns as i128;
+        let frequency = self.shader_clock_frequency.max(1) as i128;
+        let delta = -(-delta_ns * frequency).div_euclid(NS_PER_SECOND as i128);
+        (anchor_ticks as i128 + delta).clamp(0, u64::MAX as i128) as u64
+    }
+
+    /// The GPU timestamp at an SQTT timestamp, if the capture relates the two clocks.
+    pub fn sqtt_to_gpu(&self, ticks: u64) -> Option<u64> {
+        let anchor = self.sqtt_anchor?;
+        let delta = ticks as i128 - anchor.sqtt_timestamp as i128;
+        let delta_gpu =
+            (delta * self.gpu_timestamp_frequency as i128).div_euclid(self.shader_clock_frequency.max(1) as i128);
+        Some((anchor.gpu_timestamp as i128 + delta_gpu).clamp(0, u64::MAX as i128) as u64)
+    }
+
+    /// Converts a GPU timestamp to nanoseconds on the CPU clock.
+    pub fn gpu_to_cpu_ns(&self, gpu_timestamp: u64) -> Option<u64> {
+        let calibration = self.calibration.as_ref()?;
+        let delta = gpu_timestamp as i128 - calibration.gpu_timestamp as i128;
+        let delta_ns = delta * NS_PER_SECOND as i128 / self.gpu_timestamp_frequency.max(1) as i128;
+        (self.cpu_to_ns(calibration.cpu_timestamp) as i128 + delta_ns)
+            .try_into()
+            .ok()
+    }
+
+    pub fn cpu_to_ns(&self, cpu_timestamp: u64) -> u64 {
+        scale(cpu_timestamp, NS_PER_SECOND, self.cpu_timestamp_frequency)
+    }
+}
+
+fn scale(value: u64, numerator: u64, denominator: u64) -> u64 {
+    (value as u128 * numerator as u128 / denominator.max(1) as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timebase(shader_clock_frequency: u64, sqtt_anchor: Option<SqttAnchor>) -> Timebase {
+        Timebase {
+            shader_clock_frequency,
+            gpu_timestamp_frequency: 100_000_000,
+            cpu_timestamp_frequency: 10_000_000,
+            calibration: Some(RgpClockCalibration {
+                cpu_timestamp: 500,
+                gpu_timestamp: 1000,
+                reserved: 0,
+            }),
+            sqtt_anchor,
+        }
+    }
+
+    const ANCHOR: SqttAnchor = SqttAnchor {
+        sqtt_timestamp: 400,
+        gpu_timestamp: 1100,
+    };
+
+    #[test]
+    fn conversions() {
+        let timebase = timebase(100_000_000, Some(ANCHOR));
+        assert_eq!(timebase.cpu_to_ns(3), 300);
+        assert_eq!(timebase.sqtt_to_ns(250), 2500);
+        // The calibration point is at 50000 ns, the anchor 100 GPU ticks later.
+        assert_eq!(timebase.gpu_to_cpu_ns(1000), Some(50_000));
+        assert_eq!(timebase.gpu_to_cpu_ns(1100), Some(51_000));
+        assert!(timebase.sqtt_on_cpu_clock());
+        assert_eq!(timebase.sqtt_to_cpu_ns(400), 51_000);
+        assert_eq!(timebase.sqtt_to_cpu_ns(500), 52_000);
+        assert_eq!(timebase.sqtt_to_cpu_ns(0), 47_000);
+        assert_eq!(timebase.cpu_ns_to_sqtt(52_000), 500);
+        assert_eq!(timebase.cpu_ns_to_sqtt(52_001), 501);
+        assert_eq!(timebase.cpu_ns_to_sqtt(0), 0);
+        assert_eq!(timebase.sqtt_to_gpu(400), Some(1100));
+        assert_eq!(timebase.sqtt_to_gpu(500), Some(1200));
+        assert_eq!(timebase.sqtt_to_gpu(0), Some(700));
+    }
+
+    #[test]
+    fn unanchored() {
+        let mut unanchored = timebase(100_000_000, None);
+        assert!(!unanchored.sqtt_on_cpu_clock());
+        assert_eq!(unanchored.sqtt_to_cpu_ns(500), 5000);
+        assert_eq!(unanchored.cpu_ns_to_sqtt(5000), 500);
+        assert_eq!(unanchored.sqtt_to_gpu(500), None);
+
+        // An anchor is useless without a calibration to get from GPU timestamps to the CPU clock.
+        unanchored.sqtt_anchor = Some(ANCHOR);
+        unanchored.calibration = None;
+        assert_eq!(unanchored.gpu_to_cpu_ns(1100), None);
+        assert!(!unanchored.sqtt_on_cpu_clock());
+        assert_eq!(unanchored.sqtt_to_cpu_ns(500), 5000);
+    }
+
+    #[test]
+    fn round_trip() {
+        for frequency in [27_000_000u64, 100_000_000, 1_000_000_000, 2_500_000_000] {
+            // Nanoseconds are coarser than shader clocks above 1 GHz, so the round trip can land a few ticks early.
+            let max_error = frequency.div_ceil(NS_PER_SECOND) - 1;
+            for anchor in [None, Some(ANCHOR)] {
+                let timebase = timebase(frequency, anchor);
+                for ticks in (0..100_000).step_by(37) {
+                    let round_trip = timebase.cpu_ns_to_sqtt(timebase.sqtt_to_cpu_ns(ticks));
+                    assert!(
+                        round_trip <= ticks && ticks - round_trip <= max_error,
+                        "{} -> {} at {} Hz",
+                        ticks,
+                        round_trip,
+                        frequency
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use num_enum::TryFromPrimitive;
+use scroll::{Pread, LE};
+use tracing::{info, warn};
+
+use crate::clock::Timebase;
+use crate::rgp::{RgpCodeObjectLoaderEventRecord, SqttLoaderEventType};
+
+/// An AMDGPU code object (ELF binary) embedded in the capture.
+pub struct CodeObject {
+    /// The internal pipeline hash, which loader events and PSO correlations refer to.
+    pub hash: Option<[u64; 2]>,
+    pub data: Vec<u8>,
+}
+
+impl CodeObject {
+    pub fn new(data: Vec<u8>) -> Self {
+        CodeObject {
+            hash: internal_pipeline_hash(&data),
+            data,
+        }
+    }
+
+    /// The size of the code object in GPU memory: the end of its last loadable segment, or the size of the ELF if it
+    /// has no program headers.
+    pub fn load_size(&self) -> u64 {
+        elf_load_size(&self.data).unwrap_or(self.data.len() as u64)
+    }
+}
+
+fn elf_load_size(elf: &[u8]) -> Option<u64> {
+    const PT_LOAD: u32 = 1;
+
+    if !elf.starts_with(b"\x7fELF") {
+        return None;
+    }
+    let phoff: u64 = elf.pread_with(0x20, LE).ok()?;
+    let phentsize: u16 = elf.pread_with(0x36, LE).ok()?;
+    let phnum: u16 = elf.pread_with(0x38, LE).ok()?;
+    (0..phnum as u64)
+        .filter_map(|i| {
+            let phdr = usize::try_from(phoff.checked_add(i * phentsize as u64)?).ok()?;
+            let p_type: u32 = elf.pread_with(phdr, LE).ok()?;
+            let vaddr: u64 = elf.pread_with(phdr + 0x10, LE).ok()?;
+            let memsz: u64 = elf.pread_with(phdr + 0x28, LE).ok()?;
+            (p_type == PT_LOAD).then(|| vaddr.saturating_add(memsz))
+        })
+        .max()
+}
+
+/// Extracts `.internal_pipeline_hash` from the PAL metadata note of a code object.
+///
+/// The metadata is a MessagePack map. Instead of walking the ELF notes and decoding the whole map, we look for the
+/// encoded key and decode the two-element array of integers following it.
+fn internal_pipeline_hash(elf: &[u8]) -> Option<[u64; 2]> {
+    const KEY: &[u8] = b".internal_pipeline_hash";
+    const FIXARRAY_2: u8 = 0x92;
+
+    let mut encoded_key = vec![0xa0 | KEY.len() as u8]; // fixstr
+    encoded_key.extend_from_slice(KEY);
+    let position = elf.windows(encoded_key.len()).position(|w| w == encoded_key)?;
+    let mut value = &elf[position + encoded_key.len()..];
+    if *value.first()? != FIXARRAY_2 {
+        return None;
+    }
+    value = &value[1..];
+    let lo = read_msgpack_uint(&mut value)?;
+    let hi = read_msgpack_uint(&mut value)?;
+    Some([lo, hi])
+}
+
+fn read_msgpack_uint(input: &mut &[u8]) -> Option<u64> {
+    let (&marker, rest) = input.split_first()?;
+    let width = match marker {
+        0x00..=0x7f => {
+            *input = rest;
+            return Some(marker as u64);
+        }
+        0xcc => 1,
+        0xcd => 2,
+        0xce => 4,
+        0xcf => 8,
+        _ => return None,
+    };
+    let bytes = rest.get(..width)?;
+    *input = &rest[width..];
+    Some(bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u64))
+}
+
+/// A period during which a code object was loaded at `base_address`.
+pub struct Residency {
+    pub hash: [u64; 2],
+    pub base_address: u64,
+    /// Load size of the code object with `hash`, unknown if the capture doesn't contain it.
+    pub size: Option<u64>,
+    /// Nanoseconds on the CPU clock.
+    pub loaded: u64,
+    pub unloaded: Option<u64>,
+}
+
+/// Resolves GPU addresses to the code object that was resident at that address at a given time.
+#[derive(Default)]
+pub struct CodeObjectResolver {
+    /// Sorted by base address.
+    residencies: Vec<Residency>,
+}
+
+impl CodeObjectResolver {
+    pub fn new(events: &[RgpCodeObjectLoaderEventRecord], code_objects: &[CodeObject], timebase: &Timebase) -> Self {
+        let mut events = events.iter().collect::<Vec<_>>();
+        events.sort_by_key(|event| event.time_stamp);
+
+        let mut residencies: Vec<Residency> = vec![];
+        for event in events {
+            let time = timebase.cpu_to_ns(event.time_stamp);
+            match SqttLoaderEventType::try_from_primitive(event.loader_event_type) {
+                Ok(SqttLoaderEventType::CodeObjectLoadToGpuMemory) => residencies.push(Residency {
+                    hash: event.code_object_hash,
+                    base_address: event.base_address,
+                    size: code_objects
+                        .iter()
+                        .find(|code_object| code_object.hash == Some(event.code_object_hash))
+                        .map(CodeObject::load_size),
+                    loaded: time,
+                    unloaded: None,
+                }),
+                Ok(SqttLoaderEventType::CodeObjectUnloadFromGpuMemory) => {
+                    let residency = residencies
+                        .iter_mut()
+                        .rev()
+                        .find(|residency| residency.base_address == event.base_address && residency.unloaded.is_none());
+                    match residency {
+                        Some(residency) => residency.unloaded = Some(time),
+                        None => warn!(
+                            "unload of code object at {:#x} that was never loaded",
+                            event.base_address
+                        ),
+                    }
+                }
+                Err(_) => warn!("unknown loader event type {}", event.loader_event_type),
+            }
+        }
+        residencies.sort_by_key(|residency| residency.base_address);
+        CodeObjectResolver { residencies }
+    }
+
+    pub fn residencies(&self) -> &[Residency] {
+        &self.residencies
+    }
+
+    /// Finds the code object containing `address` that was resident at `time` (nanoseconds on the CPU clock). Without a
+    /// time, any code object ever loaded at that address matches. Code objects of unknown size never match.
+    pub fn resolve(&self, address: u64, time: Option<u64>) -> Option<&Residency> {
+        let end = self
+            .residencies
+            .partition_point(|residency| residency.base_address <= address);
+        self.residencies[..end].iter().rev().find(|residency| {
+            let contains = residency
+                .size
+                .is_some_and(|size| address - residency.base_address < size);
+            contains
+                && time.is_none_or(|time| {
+                    residency.loaded <= time && residency.unloaded.is_none_or(|unloaded| time < unloaded)
+                })
+        })
+    }
+}
+
+pub fn format_hash(hash: [u64; 2]) -> String {
+    format!("{:016x}{:016x}", hash[1], hash[0])
+}
+
+/// Writes each code object to `dir` as a separate ELF file.
+pub fn dump_code_objects(code_objects: &[CodeObject], dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    for (index, code_object) in code_objects.iter().enumerate() {
+        let name = match code_object.hash {
+            Some(hash) => format!("{}_{}.elf", index, format_hash(hash)),
+            None => format!("{}.elf", index),
+        };
+        fs::write(dir.join(name), &code_object.data)?;
+    }
+    info!("wrote {} code objects to {}", code_objects.len(), dir.display());
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::fs::File;
+use std::io::{stdin, Read};
+use std::ops::Deref;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
+use memmap2::Mmap;
+use tracing::warn;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The bytes of a capture file, mapped into memory when possible.
+pub enum CaptureData {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl Deref for CaptureData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            CaptureData::Mapped(mmap) => mmap,
+            CaptureData::Owned(data) => data,
+        }
+    }
+}
+
+/// Loads a capture from a file, or from stdin if `path` is `-`. Zstd and gzip compressed captures are decompressed.
+pub fn load(path: &Path) -> Result<CaptureData> {
+    let data = if path == Path::new("-") {
+        let mut data = vec![];
+        stdin().lock().read_to_end(&mut data)?;
+        CaptureData::Owned(data)
+    } else {
+        map(path)?
+    };
+    decompress(data)
+}
+
+fn decompress(data: CaptureData) -> Result<CaptureData> {
+    if data.starts_with(&ZSTD_MAGIC) {
+        let data = zstd::stream::decode_all(&data[..]).context("Failed to decompress zstd input")?;
+        Ok(CaptureData::Owned(data))
+    } else if data.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = vec![];
+        MultiGzDecoder::new(&data[..])
+            .read_to_end(&mut decompressed)
+            .context("Failed to decompress gzip input")?;
+        Ok(CaptureData::Owned(decompressed))
+    } else {
+        Ok(data)
+    }
+}
+
+/// Maps a capture file into memory, falling back to reading it for inputs that can't be mapped, like pipes.
+fn map(path: &Path) -> Result<CaptureData> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    if file.metadata()?.is_file() {
+        // SAFETY: The capture is only read. Truncating or modifying the file while we are running is unsupported,
+        // just like for any other tool that maps its input.
+        match unsafe { Mmap::map(&file) } {
+            Ok(mmap) => return Ok(CaptureData::Mapped(mmap)),
+            Err(err) => warn!("failed to map {}, reading it instead: {}", path.display(), err),
+        }
+    }
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+    Ok(CaptureData::Owned(data))
+}
@@ -1,24 +1,47 @@
+use std::collections::HashMap;
 use std::env::args_os;
 use std::fs;
+use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, Result};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use scroll::{Pread, LE};
 use tonic::transport::Server;
 use tonic::{Request, Response, Status};
 use tracing::log::warn;
 
-use crate::merge::{MergedIterator, MergedIteratorItem};
+use crate::clock::{SqttAnchor, Timebase};
+use crate::code_object::{dump_code_objects, CodeObject, CodeObjectResolver};
+use crate::input::load;
 use crate::proto::sqtt_service_server::*;
-use crate::proto::{Event, GetEventsRequest, GetEventsResponse};
-use crate::rgp::{RgpAsicInfo, RgpEntryHeader, RgpHeader, SqttFileChunkType, ENTRY_HEADER_SIZE};
-use crate::sqtt::{parse_sqtt, SqttChunk};
-use crate::userdata::SqttUserdata;
+use crate::proto::{
+    CodeObjectLoad, Event, GetCaptureInfoRequest, GetCaptureInfoResponse, GetCodeObjectsRequest,
+    GetCodeObjectsResponse, GetEventsRequest, GetEventsResponse, GetInitiatorsRequest, GetInitiatorsResponse,
+    GetProgramCountersRequest, GetProgramCountersResponse, GetQueueEventsRequest, GetQueueEventsResponse,
+    GetSpmCountersRequest, GetSpmCountersResponse, GetWavesRequest, GetWavesResponse, ProgramCounter, Queue,
+    QueueEvent, SpmCounter,
+};
+use crate::rgp::{
+    c_str, parse_code_object_database, parse_code_object_loader_events, parse_pso_correlation,
+    parse_queue_event_timings, parse_spm_db, validate, RgpApiInfo, RgpAsicInfo, RgpCpuInfo, RgpEntryHeader, RgpError,
+    RgpInstrumentationTable, RgpPsoCorrelationRecord, RgpQueueEventRecord, RgpQueueInfoRecord, RgpSpmTrace,
+    RgpSqttData, RgpSqttDesc, RgpUnknownChunk, SqttFileChunkType, SqttQueueEventType, ENTRY_HEADER_SIZE,
+    SQTT_DATA_HEADER_SIZE,
+};
+use crate::sqtt::{parse_sqtt, SqttChunk, SqttEncoding, SqttOptions};
+use crate::userdata::{check_instrumentation_version, decode_markers, RgpSqttMarkerIdentifier};
+use crate::wave::reconstruct_waves;
+use crate::writer::{anonymize, trim};
 
+mod clock;
+mod code_object;
+mod input;
 mod merge;
 mod rgp;
 mod sqtt;
 mod userdata;
+mod wave;
+mod writer;
 
 pub mod proto {
     tonic::include_proto!("sqtt.v2");
@@ -26,101 +49,630 @@ pub mod proto {
 
 struct Service {
     asic_info: RgpAsicInfo,
+    sqtt_options: SqttOptions,
+    api_info: Option<RgpApiInfo>,
+    cpu_info: Option<RgpCpuInfo>,
+    queues: Vec<RgpQueueInfoRecord>,
+    queue_events: Vec<RgpQueueEventRecord>,
+    timebase: Timebase,
+    code_objects: Vec<CodeObject>,
+    code_object_resolver: CodeObjectResolver,
+    /// Keyed by API PSO hash.
+    pso_correlations: HashMap<u64, RgpPsoCorrelationRecord>,
+    spm_trace: Option<RgpSpmTrace>,
+    /// Headers of all chunks with their file offset.
+    inventory: Vec<(usize, RgpEntryHeader)>,
+    unknown_chunks: Vec<RgpUnknownChunk>,
     chunks: Vec<SqttChunk>,
 }
 
+impl Service {
+    /// Fills in the pipeline name and code objects of a BindPipeline event.
+    fn label_pipeline(&self, event: &mut Event, api_pso_hash: u64) {
+        event.api_pso_hash = api_pso_hash;
+        let Some(correlation) = self.pso_correlations.get(&api_pso_hash) else {
+            return;
+        };
+        event.pipeline_name = c_str(&correlation.api_level_obj_name);
+        event.code_objects = self
+            .code_objects
+            .iter()
+            .enumerate()
+            .filter(|(_, code_object)| code_object.hash == Some(correlation.pipeline_hash))
+            .map(|(index, _)| index as u32)
+            .collect();
+    }
+
+    fn chunk_inventory(&self) -> Vec<proto::Chunk> {
+        self.inventory
+            .iter()
+            .map(|(offset, header)| {
+                let type_name = match SqttFileChunkType::try_from(header.chunk_id.ty) {
+                    Ok(SqttFileChunkType::Count) | Err(_) => String::new(),
+                    Ok(ty) => format!("{:?}", ty),
+                };
+                let data = self
+                    .unknown_chunks
+                    .iter()
+                    .find(|chunk| chunk.offset == *offset)
+                    .map(|chunk| chunk.data.clone())
+                    .unwrap_or_default();
+                proto::Chunk {
+                    r#type: header.chunk_id.ty as u32,
+                    type_name,
+                    index: header.chunk_id.index as u32,
+                    version_major: header.version_major as u32,
+                    version_minor: header.version_minor as u32,
+                    offset: *offset as u64,
+                    size: header.size as u64,
+                    data,
+                }
+            })
+            .collect()
+    }
+
+    fn decode_gaps(&self) -> Vec<proto::DecodeGap> {
+        self.chunks
+            .iter()
+            .flat_map(|chunk| {
+                chunk.gaps.iter().map(|gap| proto::DecodeGap {
+                    shader_engine: chunk.desc.shader_engine_index,
+                    bit_offset: gap.bit_offset as u64,
+                    bit_len: gap.bit_len as u64,
+                    time: self.timebase.sqtt_to_cpu_ns(gap.timestamp),
+                })
+            })
+            .collect()
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn chunk(&self, shader_engine: u32) -> std::result::Result<&SqttChunk, Status> {
+        self.chunks
+            .iter()
+            .find(|chunk| chunk.desc.shader_engine_index == shader_engine)
+            .ok_or_else(|| Status::not_found(format!("No trace for shader engine {}", shader_engine)))
+    }
+}
+
+impl From<&RgpAsicInfo> for proto::AsicInfo {
+    fn from(info: &RgpAsicInfo) -> Self {
+        proto::AsicInfo {
+            gpu_name: c_str(&info.gpu_name),
+            device_id: info.device_id,
+            device_revision_id: info.device_revision_id,
+            gfxip_level: info.gfxip_level,
+            shader_engines: info.shader_engines,
+            compute_unit_per_shader_engine: info.compute_unit_per_shader_engine,
+            simd_per_compute_unit: info.simd_per_compute_unit,
+            wavefronts_per_simd: info.wavefronts_per_simd,
+            trace_shader_core_clock: info.trace_shader_core_clock,
+            trace_memory_clock: info.trace_memory_clock,
+            gpu_timestamp_frequency: info.gpu_timestamp_frequency,
+            vram_size: info.vram_size,
+        }
+    }
+}
+
+impl From<&RgpApiInfo> for proto::ApiInfo {
+    fn from(info: &RgpApiInfo) -> Self {
+        proto::ApiInfo {
+            api_type: info.api_type as i32,
+            api_version_major: info.api_version_major as u32,
+            api_version_minor: info.api_version_minor as u32,
+            profiling_mode: info.profiling_mode as i32,
+            instruction_trace_mode: info.instruction_trace_mode as i32,
+        }
+    }
+}
+
+impl From<&RgpCpuInfo> for proto::CpuInfo {
+    fn from(info: &RgpCpuInfo) -> Self {
+        proto::CpuInfo {
+            vendor_id: c_str(&info.vendor_id),
+            processor_brand: c_str(&info.processor_brand),
+            cpu_timestamp_frequency: info.cpu_timestamp_freq,
+            clock_speed_mhz: info.clock_speed,
+            num_logical_cores: info.num_logical_cores,
+            num_physical_cores: info.num_physical_cores,
+            system_ram_size_mib: info.system_ram_size,
+        }
+    }
+}
+
+impl From<&RgpQueueEventRecord> for QueueEvent {
+    fn from(event: &RgpQueueEventRecord) -> Self {
+        QueueEvent {
+            r#type: event.event_type as i32,
+            sqtt_cb_id: event.sqtt_cb_id,
+            frame_index: event.frame_index,
+            submit_sub_index: event.submit_sub_index,
+            api_id: event.api_id,
+            cpu_timestamp: event.cpu_timestamp,
+            gpu_start: event.gpu_timestamps[0],
+            gpu_end: event.gpu_timestamps[1],
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+/// The part of a capture to keep when trimming.
+enum TrimRange {
+    /// Nanoseconds in the time base the RPCs report SQTT times in.
+    Time { start: u64, end: u64 },
+    /// From the start of one command buffer to the end of another.
+    CommandBuffers { start: u32, end: u32 },
+}
+
+impl Service {
+    /// The SQTT timestamps bounding `range`.
+    fn trim_window(&self, range: &TrimRange) -> Result<(u64, u64)> {
+        match *range {
+            TrimRange::Time { start, end } => {
+                Ok((self.timebase.cpu_ns_to_sqtt(start), self.timebase.cpu_ns_to_sqtt(end)))
+            }
+            TrimRange::CommandBuffers { start, end } => {
+                let markers: Vec<_> = self.chunks.iter().flat_map(decode_markers).collect();
+                let find = |id: RgpSqttMarkerIdentifier, cb_id| {
+                    markers
+                        .iter()
+                        .filter(move |marker| marker.userdata.id() == id && marker.userdata.cb_id() == Some(cb_id))
+                };
+                let window_start = find(RgpSqttMarkerIdentifier::CbStart, start)
+                    .map(|marker| marker.first_timestamp)
+                    .min()
+                    .ok_or_else(|| anyhow!("No CbStart marker for command buffer {}", start))?;
+                let window_end = find(RgpSqttMarkerIdentifier::CbEnd, end)
+                    .map(|marker| marker.timestamp)
+                    .max()
+                    .ok_or_else(|| anyhow!("No CbEnd marker for command buffer {}", end))?;
+                Ok((window_start, window_end))
+            }
+        }
+    }
+}
+
 #[tonic::async_trait]
 impl SqttService for Service {
     async fn get_events(
         &self,
-        _request: Request<GetEventsRequest>,
+        request: Request<GetEventsRequest>,
     ) -> std::result::Result<Response<GetEventsResponse>, Status> {
-        const SQ_THREAD_TRACE_USERDATA_2: u32 = 0x030D08 / 4;
-        const SQ_THREAD_TRACE_USERDATA_3: u32 = 0x030D0C / 4;
-        const THREAD_TRACE_MARKER: u32 = 53;
-
-        let reg_write = &self.chunks[0].reg_write;
-        let initiator = &self.chunks[0].initiator;
-        let iter = MergedIterator::new(vec![&reg_write.seq, &initiator.seq]);
-
-        let mut userdata_buf = vec![];
-        let mut events = vec![];
-        for MergedIteratorItem { kind, index: i } in iter {
-            match kind {
-                0 => {
-                    if reg_write.reg[i] as u32 == SQ_THREAD_TRACE_USERDATA_2
-                        || reg_write.reg[i] as u32 == SQ_THREAD_TRACE_USERDATA_3
-                    {
-                        userdata_buf.push(reg_write.val[i]);
-                        if SqttUserdata::len(userdata_buf[0]).unwrap() == userdata_buf.len() {
-                            events.push(Event {
-                                r#type: SqttUserdata::new(userdata_buf).unwrap().api_type(),
-                                start: 0,
-                                end: 0,
-                            });
-                            userdata_buf = vec![];
-                        }
-                    }
+        let chunk = self.chunk(request.into_inner().shader_engine)?;
+        let events = decode_markers(chunk)
+            .into_iter()
+            .map(|marker| {
+                let mut event = Event {
+                    r#type: marker.userdata.api_type(),
+                    start: self.timebase.sqtt_to_cpu_ns(marker.first_timestamp),
+                    end: self.timebase.sqtt_to_cpu_ns(marker.timestamp),
+                    ..Default::default()
+                };
+                if let Some(api_pso_hash) = marker.userdata.api_pso_hash() {
+                    self.label_pipeline(&mut event, api_pso_hash);
                 }
-                1 => {
-                    if initiator.initiator_type[i] == 0 && (initiator.val[i] & 0xfffff) == THREAD_TRACE_MARKER {
-                        if !userdata_buf.is_empty() {
-                            warn!("encountered initiator but userdata packet is incomplete");
-                            userdata_buf.clear(); // Try to re-synchronize
-                        }
-                    }
-                }
-                _ => unreachable!(),
+                event
+            })
+            .collect();
+        Ok(Response::new(GetEventsResponse { events }))
+    }
+
+    async fn get_capture_info(
+        &self,
+        _request: Request<GetCaptureInfoRequest>,
+    ) -> std::result::Result<Response<GetCaptureInfoResponse>, Status> {
+        let api_info = self.api_info.as_ref().map(Into::into);
+        Ok(Response::new(GetCaptureInfoResponse {
+            asic_info: Some((&self.asic_info).into()),
+            api_info,
+            cpu_info: self.cpu_info.as_ref().map(Into::into),
+            chunks: self.chunk_inventory(),
+            decode_gaps: self.decode_gaps(),
+            sqtt_on_cpu_clock: self.timebase.sqtt_on_cpu_clock(),
+        }))
+    }
+
+    async fn get_queue_events(
+        &self,
+        _request: Request<GetQueueEventsRequest>,
+    ) -> std::result::Result<Response<GetQueueEventsResponse>, Status> {
+        let mut queues = self
+            .queues
+            .iter()
+            .map(|queue| Queue {
+                queue_id: queue.queue_id,
+                queue_context: queue.queue_context,
+                queue_type: queue.queue_type as i32,
+                engine_type: queue.engine_type as i32,
+                events: vec![],
+            })
+            .collect::<Vec<_>>();
+        // Unused GPU timestamps are left as zero.
+        let to_cpu_ns = |timestamp| match timestamp {
+            0 => 0,
+            _ => self.timebase.gpu_to_cpu_ns(timestamp).unwrap_or(0),
+        };
+        for event in &self.queue_events {
+            match queues.get_mut(event.queue_info_index as usize) {
+                Some(queue) => queue.events.push(QueueEvent {
+                    start: to_cpu_ns(event.gpu_timestamps[0]),
+                    end: to_cpu_ns(event.gpu_timestamps[1]),
+                    ..event.into()
+                }),
+                None => warn!("queue event refers to unknown queue {}", event.queue_info_index),
             }
         }
-        Ok(Response::new(GetEventsResponse { events }))
+        for queue in &mut queues {
+            queue.events.sort_by_key(|event| event.cpu_timestamp);
+        }
+        Ok(Response::new(GetQueueEventsResponse { queues }))
+    }
+
+    async fn get_code_objects(
+        &self,
+        request: Request<GetCodeObjectsRequest>,
+    ) -> std::result::Result<Response<GetCodeObjectsResponse>, Status> {
+        let include_data = request.into_inner().include_data;
+        let code_objects = self
+            .code_objects
+            .iter()
+            .enumerate()
+            .map(|(index, code_object)| {
+                let hash = code_object.hash.unwrap_or_default();
+                let loads = self
+                    .code_object_resolver
+                    .residencies()
+                    .iter()
+                    .filter(|residency| code_object.hash == Some(residency.hash))
+                    .map(|residency| CodeObjectLoad {
+                        base_address: residency.base_address,
+                        loaded: residency.loaded,
+                        unloaded: residency.unloaded.unwrap_or(0),
+                    })
+                    .collect();
+                proto::CodeObject {
+                    index: index as u32,
+                    hash_lo: hash[0],
+                    hash_hi: hash[1],
+                    size: code_object.data.len() as u64,
+                    data: if include_data { code_object.data.clone() } else { vec![] },
+                    loads,
+                }
+            })
+            .collect();
+        Ok(Response::new(GetCodeObjectsResponse { code_objects }))
+    }
+
+    async fn get_program_counters(
+        &self,
+        request: Request<GetProgramCountersRequest>,
+    ) -> std::result::Result<Response<GetProgramCountersResponse>, Status> {
+        let set_pc = &self.chunk(request.into_inner().shader_engine)?.set_pc;
+        let program_counters = (0..set_pc.seq.len())
+            .map(|i| {
+                let time = self.timebase.sqtt_to_cpu_ns(set_pc.timestamp[i]);
+                let pc = set_pc.pc[i];
+                let mut ret = ProgramCounter {
+                    wave: set_pc.wave[i] as u32,
+                    time,
+                    pc,
+                    code_object_index: -1,
+                    ..Default::default()
+                };
+                let resolve_time = self.timebase.sqtt_on_cpu_clock().then_some(time);
+                if let Some(residency) = self.code_object_resolver.resolve(pc, resolve_time) {
+                    ret.hash_lo = residency.hash[0];
+                    ret.hash_hi = residency.hash[1];
+                    ret.offset = pc - residency.base_address;
+                    if let Some(index) = self
+                        .code_objects
+                        .iter()
+                        .position(|code_object| code_object.hash == Some(residency.hash))
+                    {
+                        ret.code_object_index = index as i32;
+                    }
+                }
+                ret
+            })
+            .collect();
+        Ok(Response::new(GetProgramCountersResponse { program_counters }))
+    }
+
+    async fn get_initiators(
+        &self,
+        request: Request<GetInitiatorsRequest>,
+    ) -> std::result::Result<Response<GetInitiatorsResponse>, Status> {
+        let initiator = &self.chunk(request.into_inner().shader_engine)?.initiator;
+        let initiators = (0..initiator.seq.len())
+            .map(|i| proto::Initiator {
+                r#type: initiator.initiator_type[i] as i32,
+                id: initiator.id(i),
+                context: initiator.context[i] as u32,
+                time: self.timebase.sqtt_to_cpu_ns(initiator.timestamp[i]),
+            })
+            .collect();
+        Ok(Response::new(GetInitiatorsResponse { initiators }))
+    }
+
+    async fn get_waves(
+        &self,
+        request: Request<GetWavesRequest>,
+    ) -> std::result::Result<Response<GetWavesResponse>, Status> {
+        let chunk = self.chunk(request.into_inner().shader_engine)?;
+        let to_ns = |timestamp: Option<u64>| timestamp.map_or(0, |timestamp| self.timebase.sqtt_to_cpu_ns(timestamp));
+        let waves = reconstruct_waves(chunk)
+            .into_iter()
+            .map(|wave| proto::Wave {
+                sh: wave.slot.sh as u32,
+                simd: wave.slot.simd as u32,
+                wgp: wave.slot.wgp as u32,
+                wave: wave.slot.wave as u32,
+                alloc: to_ns(wave.alloc),
+                start: to_ns(wave.start),
+                end: to_ns(wave.end),
+                stage: wave.stage as u32,
+                threads: wave.threads as u32,
+            })
+            .collect();
+        Ok(Response::new(GetWavesResponse { waves }))
+    }
+
+    async fn get_spm_counters(
+        &self,
+        _request: Request<GetSpmCountersRequest>,
+    ) -> std::result::Result<Response<GetSpmCountersResponse>, Status> {
+        let spm_trace = self
+            .spm_trace
+            .as_ref()
+            .ok_or_else(|| Status::not_found("Capture has no SPM data"))?;
+        let timestamps = spm_trace
+            .timestamps
+            .iter()
+            .map(|&timestamp| self.timebase.gpu_to_cpu_ns(timestamp))
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default();
+        let counters = spm_trace
+            .counters
+            .iter()
+            .map(|counter| SpmCounter {
+                block: counter.info.block,
+                instance: counter.info.instance,
+                event_index: counter.info.event_index,
+                values: counter.values.iter().map(|&value| value as u32).collect(),
+            })
+            .collect();
+        Ok(Response::new(GetSpmCountersResponse {
+            sample_interval: spm_trace.header.sample_interval,
+            gpu_timestamps: spm_trace.timestamps.clone(),
+            timestamps,
+            counters,
+        }))
     }
 }
 
-fn parse(data: &[u8]) -> Result<Service> {
+/// Relates the SQTT clock to GPU timestamps through the earliest command buffer whose CbStart marker is in the trace
+/// and whose submission has a GPU timestamp.
+fn find_sqtt_anchor(chunks: &[SqttChunk], queue_events: &[RgpQueueEventRecord]) -> Option<SqttAnchor> {
+    chunks
+        .iter()
+        .flat_map(decode_markers)
+        .filter(|marker| marker.userdata.id() == RgpSqttMarkerIdentifier::CbStart)
+        .filter_map(|marker| {
+            let cb_id = marker.userdata.cb_id()?;
+            let submit = queue_events.iter().find(|event| {
+                event.event_type == SqttQueueEventType::CmdbufSubmit.into()
+                    && event.sqtt_cb_id == cb_id
+                    && event.gpu_timestamps[0] != 0
+            })?;
+            Some(SqttAnchor {
+                sqtt_timestamp: marker.first_timestamp,
+                gpu_timestamp: submit.gpu_timestamps[0],
+            })
+        })
+        .min_by_key(|anchor| anchor.sqtt_timestamp)
+}
+
+fn parse(data: &[u8], best_effort: bool, resync: bool) -> Result<Service> {
     use SqttFileChunkType::*;
-    let hdr: RgpHeader = data.pread_with(0, LE)?;
+    let (_, inventory) = validate(data)?;
     let mut asic_info = None;
-    let mut offset = hdr.chunk_offset as usize;
+    let mut api_info = None;
+    let mut queues = vec![];
+    let mut queue_events = vec![];
+    let mut clock_calibration = None;
+    let mut cpu_info = None;
+    let mut code_objects = vec![];
+    let mut loader_events = vec![];
+    let mut pso_correlations = HashMap::new();
+    let mut spm_trace = None;
+    let mut unknown_chunks = vec![];
+    let mut sqtt_descs = vec![];
     let mut sqtt_chunks = vec![];
-    while offset < data.len() {
-        let entry: RgpEntryHeader = data.pread_with(offset, LE)?;
-        if entry.size < ENTRY_HEADER_SIZE as _ {
-            bail!("Corrupt chunk (size too small)");
-        }
-        let len = (entry.size as usize) - ENTRY_HEADER_SIZE;
-        let chunk_type: SqttFileChunkType = entry.chunk_id.ty.try_into()?;
-        match chunk_type {
-            AsicInfo => {
-                let start = offset + ENTRY_HEADER_SIZE;
-                asic_info = Some(data.pread_with(start, LE)?);
+    for (index, (offset, entry)) in inventory.iter().enumerate() {
+        let chunk = &data[offset + ENTRY_HEADER_SIZE..offset + entry.size as usize];
+        // Newer versions of the format add chunk types and bump chunk versions; keep those verbatim instead of failing
+        // to load the capture. `validate` has already rejected unknown versions of the chunks we can't do without.
+        let chunk_type = match entry.known_type() {
+            None => {
+                warn!(
+                    "skipping chunk of unknown type {} version {}.{} at offset {}",
+                    entry.chunk_id.ty, entry.version_major, entry.version_minor, offset
+                );
+                unknown_chunks.push(RgpUnknownChunk {
+                    offset: *offset,
+                    header: entry.clone(),
+                    data: chunk.to_vec(),
+                });
+                continue;
             }
-            SqttData => {
-                let start = offset + ENTRY_HEADER_SIZE + 8;
-                let sqtt_data = &data[start..offset + len];
-                sqtt_chunks.push(sqtt_data);
+            Some(chunk_type) => chunk_type,
+        };
+        let mut parse_chunk = || -> std::result::Result<(), scroll::Error> {
+            match chunk_type {
+                AsicInfo => asic_info = Some(chunk.pread_with(0, LE)?),
+                ApiInfo => api_info = Some(chunk.pread_with(0, LE)?),
+                QueueEventTimings => (queues, queue_events) = parse_queue_event_timings(chunk)?,
+                ClockCalibration => clock_calibration = Some(chunk.pread_with(0, LE)?),
+                CpuInfo => cpu_info = Some(chunk.pread_with(0, LE)?),
+                CodeObjectDatabase => {
+                    let records = parse_code_object_database(chunk)?;
+                    code_objects.extend(records.into_iter().map(|record| CodeObject::new(record.to_vec())));
+                }
+                CodeObjectLoaderEvents => loader_events.extend(parse_code_object_loader_events(chunk)?),
+                PsoCorrelation => {
+                    let records = parse_pso_correlation(chunk)?;
+                    pso_correlations.extend(records.into_iter().map(|record| (record.api_pso_hash, record)));
+                }
+                SpmDb => spm_trace = Some(parse_spm_db(chunk)?),
+                InstrumentationTable => {
+                    let table: RgpInstrumentationTable = chunk.pread_with(0, LE)?;
+                    check_instrumentation_version(
+                        table.instrumentation_spec_version,
+                        table.instrumentation_api_version,
+                    );
+                }
+                SqttDesc => {
+                    let desc: RgpSqttDesc = chunk.pread_with(0, LE)?;
+                    check_instrumentation_version(desc.instrumentation_spec_version, desc.instrumentation_api_version);
+                    sqtt_descs.push((entry.chunk_id.index, desc));
+                }
+                SqttData => {
+                    let header: RgpSqttData = chunk.pread_with(0, LE)?;
+                    let sqtt_data: &[u8] = chunk.pread_with(SQTT_DATA_HEADER_SIZE, header.size as usize)?;
+                    sqtt_chunks.push((entry.chunk_id.index, sqtt_data));
+                }
+                _ => {}
             }
-            _ => {}
-        }
-        offset += entry.size as usize;
+            Ok(())
+        };
+        parse_chunk().map_err(|source| RgpError::MalformedChunk {
+            index,
+            offset: *offset,
+            ty: entry.chunk_id.ty,
+            source,
+        })?;
     }
-    let asic_info = asic_info.ok_or_else(|| anyhow!("No asic info found"))?;
+    let asic_info = asic_info.ok_or(RgpError::MissingChunk(AsicInfo))?;
+    // Each SqttData chunk is described by the SqttDesc chunk with the same index.
+    let sqtt_chunks = sqtt_chunks
+        .into_iter()
+        .map(|(index, chunk)| {
+            let desc = sqtt_descs
+                .iter()
+                .find(|(desc_index, _)| *desc_index == index)
+                .map(|(_, desc)| desc.clone())
+                .ok_or(RgpError::MissingSqttDesc(index))?;
+            Ok((chunk, desc))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let sqtt_options = SqttOptions {
+        encoding: SqttEncoding::new(&asic_info, best_effort)?,
+        resync,
+    };
     let chunks = sqtt_chunks
         .into_par_iter()
-        .map(|chunk| parse_sqtt(chunk, &asic_info))
+        .map(|(chunk, desc)| parse_sqtt(chunk, desc, sqtt_options))
         .collect::<Result<Vec<_>>>()?;
-    Ok(Service { asic_info, chunks })
+    let sqtt_anchor = find_sqtt_anchor(&chunks, &queue_events);
+    let timebase = Timebase::new(&asic_info, cpu_info.as_ref(), clock_calibration, sqtt_anchor);
+    if !timebase.sqtt_on_cpu_clock() {
+        warn!("Can't align the SQTT clock with the CPU clock, reporting SQTT times relative to the start of the trace");
+    }
+    let code_object_resolver = CodeObjectResolver::new(&loader_events, &code_objects, &timebase);
+    Ok(Service {
+        asic_info,
+        sqtt_options,
+        api_info,
+        cpu_info,
+        queues,
+        queue_events,
+        timebase,
+        code_objects,
+        code_object_resolver,
+        pso_correlations,
+        spm_trace,
+        inventory,
+        unknown_chunks,
+        chunks,
+    })
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    let file = args_os().nth(1).ok_or_else(|| anyhow!("Missing file argument"))?;
-    let buf = fs::read(file)?;
+    let mut file = None;
+    let mut dump_dir = None;
+    let mut anonymize_output = None;
+    let mut trim_args = None;
+    let mut best_effort = false;
+    let mut resync = false;
+    let mut args = args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--dump-code-objects" {
+            let dir = args
+                .next()
+                .ok_or_else(|| anyhow!("Missing directory for --dump-code-objects"))?;
+            dump_dir = Some(PathBuf::from(dir));
+        } else if arg == "--best-effort" {
+            best_effort = true;
+        } else if arg == "--resync" {
+            resync = true;
+        } else if arg == "--anonymize" {
+            let output = args
+                .next()
+                .ok_or_else(|| anyhow!("Missing output file for --anonymize"))?;
+            anonymize_output = Some(PathBuf::from(output));
+        } else if arg == "--trim-time" || arg == "--trim-cb" {
+            let mut next = || {
+                args.next()
+                    .and_then(|arg| arg.into_string().ok())
+                    .ok_or_else(|| anyhow!("Usage: {} <start> <end> <output>", arg.to_string_lossy()))
+            };
+            let (start, end, output) = (next()?, next()?, next()?);
+            let range = if arg == "--trim-time" {
+                TrimRange::Time {
+                    start: start.parse()?,
+                    end: end.parse()?,
+                }
+            } else {
+                TrimRange::CommandBuffers {
+                    start: start.parse()?,
+                    end: end.parse()?,
+                }
+            };
+            trim_args = Some((range, PathBuf::from(output)));
+        } else {
+            file = Some(arg);
+        }
+    }
+    let file = file.ok_or_else(|| anyhow!("Missing file argument"))?;
+    let buf = load(Path::new(&file))?;
+
+    if let Some(output) = anonymize_output {
+        return Ok(fs::write(output, anonymize(&buf)?)?);
+    }
 
     let addr = "[::1]:50051".parse()?;
-    let greeter = parse(&buf)?;
+    let greeter = parse(&buf, best_effort, resync)?;
+
+    if let Some(dir) = dump_dir {
+        return dump_code_objects(&greeter.code_objects, &dir);
+    }
+    if let Some((range, output)) = trim_args {
+        let (start, end) = greeter.trim_window(&range)?;
+        let gpu_window = greeter
+            .timebase
+            .sqtt_to_gpu(start)
+            .zip(greeter.timebase.sqtt_to_gpu(end));
+        if gpu_window.is_none() {
+            warn!("SQTT times can't be related to GPU timestamps; keeping all SPM samples and queue events");
+        }
+        return Ok(fs::write(
+            output,
+            trim(&buf, greeter.sqtt_options, start, end, gpu_window)?,
+        )?);
+    }
 
     Server::builder()
         .accept_http1(true)
@@ -1,7 +1,51 @@
+//! On-disk structures of the RGP capture format.
+//!
+//! The structs mirror the file layout, so not every field is consumed by the rest of the backend.
+
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use scroll::Pread;
+use scroll::ctx::{SizeWith, TryIntoCtx};
+use scroll::{Endian, Pread, Pwrite, SizeWith, LE};
+use thiserror::Error;
+
+pub const RGP_MAGIC_NUMBER: u32 = 0x50303042;
+pub const RGP_VERSION_MAJOR: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum RgpError {
+    #[error("File is too short for the RGP header")]
+    TruncatedHeader,
+    #[error("Bad magic number {0:#010x}, not an RGP file")]
+    BadMagic(u32),
+    #[error("Unsupported RGP file version {major}.{minor}")]
+    UnsupportedVersion { major: u32, minor: u32 },
+    #[error("Chunk {index} at offset {offset}: truncated chunk header")]
+    TruncatedChunkHeader { index: usize, offset: usize },
+    #[error("Chunk {index} at offset {offset}: size {size} is smaller than the chunk header")]
+    ChunkTooSmall { index: usize, offset: usize, size: u32 },
+    #[error("Chunk {index} at offset {offset}: size {size} exceeds the end of the file")]
+    ChunkOutOfBounds { index: usize, offset: usize, size: u32 },
+    #[error("Chunk {index} at offset {offset}: unsupported {ty:?} chunk version {major}.{minor}")]
+    UnsupportedChunkVersion {
+        index: usize,
+        offset: usize,
+        ty: SqttFileChunkType,
+        major: u16,
+        minor: u16,
+    },
+    #[error("Chunk {index} at offset {offset}: malformed chunk of type {ty}")]
+    MalformedChunk {
+        index: usize,
+        offset: usize,
+        ty: u8,
+        source: scroll::Error,
+    },
+    #[error("No {0:?} chunk found")]
+    MissingChunk(SqttFileChunkType),
+    #[error("No SqttDesc chunk found for SqttData chunk with index {0}")]
+    MissingSqttDesc(u8),
+}
 
-#[derive(Clone, Debug, Pread)]
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
 pub struct RgpHeader {
     pub magic_number: u32,
     pub version_major: u32,
@@ -19,7 +63,7 @@ pub struct RgpHeader {
     pub is_daylight_savings: u32,
 }
 
-#[derive(TryFromPrimitive, IntoPrimitive, Debug)]
+#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum SqttFileChunkType {
     AsicInfo,
@@ -38,25 +82,114 @@ pub enum SqttFileChunkType {
     Count,
 }
 
-#[derive(Clone, Debug, Pread)]
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
 pub struct ChunkId {
     pub ty: u8,
     pub index: u8,
     pub reserved: u16,
 }
 
-#[derive(Clone, Debug, Pread)]
+impl SqttFileChunkType {
+    /// The newest major version of the chunk whose layout we know. Minor versions only append fields.
+    fn max_version_major(self) -> Option<u16> {
+        use SqttFileChunkType::*;
+        match self {
+            AsicInfo | SqttDesc | SqttData | ApiInfo | ClockCalibration | CpuInfo | CodeObjectDatabase
+            | PsoCorrelation => Some(0),
+            QueueEventTimings | CodeObjectLoaderEvents => Some(1),
+            SpmDb => Some(2),
+            Reserved | InstrumentationTable | Count => None,
+        }
+    }
+
+    /// Chunks a capture can't be decoded without. Newer major versions of the other chunks are skipped instead.
+    fn is_essential(self) -> bool {
+        matches!(
+            self,
+            SqttFileChunkType::AsicInfo | SqttFileChunkType::SqttDesc | SqttFileChunkType::SqttData
+        )
+    }
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
 pub struct RgpEntryHeader {
     pub chunk_id: ChunkId,
-    pub version_major: u16,
     pub version_minor: u16,
+    pub version_major: u16,
     pub size: u32,
     pub reserved: u32,
 }
 
+impl RgpEntryHeader {
+    /// The type of the chunk, if we know both the type and the layout of its major version.
+    pub fn known_type(&self) -> Option<SqttFileChunkType> {
+        match SqttFileChunkType::try_from(self.chunk_id.ty) {
+            Ok(SqttFileChunkType::Count) | Err(_) => None,
+            Ok(ty) => ty
+                .max_version_major()
+                .is_none_or(|max| self.version_major <= max)
+                .then_some(ty),
+        }
+    }
+}
+
 pub const ENTRY_HEADER_SIZE: usize = 16;
 
-#[derive(TryFromPrimitive, IntoPrimitive, Debug)]
+/// Checks the file header, the bounds of every chunk and the versions of the essential ones.
+/// Returns the file header and the header of each chunk together with its offset.
+pub fn validate(data: &[u8]) -> Result<(RgpHeader, Vec<(usize, RgpEntryHeader)>), RgpError> {
+    let hdr: RgpHeader = data.pread_with(0, LE).map_err(|_| RgpError::TruncatedHeader)?;
+    if hdr.magic_number != RGP_MAGIC_NUMBER {
+        return Err(RgpError::BadMagic(hdr.magic_number));
+    }
+    if hdr.version_major != RGP_VERSION_MAJOR {
+        return Err(RgpError::UnsupportedVersion {
+            major: hdr.version_major,
+            minor: hdr.version_minor,
+        });
+    }
+
+    let mut chunks = vec![];
+    let mut offset = hdr.chunk_offset as usize;
+    while offset < data.len() {
+        let index = chunks.len();
+        let entry: RgpEntryHeader = data
+            .pread_with(offset, LE)
+            .map_err(|_| RgpError::TruncatedChunkHeader { index, offset })?;
+        let size = entry.size;
+        if (size as usize) < ENTRY_HEADER_SIZE {
+            return Err(RgpError::ChunkTooSmall { index, offset, size });
+        }
+        if size as usize > data.len() - offset {
+            return Err(RgpError::ChunkOutOfBounds { index, offset, size });
+        }
+        if let Ok(ty) = SqttFileChunkType::try_from(entry.chunk_id.ty) {
+            if ty.is_essential() && entry.known_type().is_none() {
+                return Err(RgpError::UnsupportedChunkVersion {
+                    index,
+                    offset,
+                    ty,
+                    major: entry.version_major,
+                    minor: entry.version_minor,
+                });
+            }
+        }
+        chunks.push((offset, entry));
+        offset += size as usize;
+    }
+    Ok((hdr, chunks))
+}
+
+/// A chunk of a type we don't know about (e.g. from a newer version of the format), kept verbatim.
+#[derive(Clone, Debug)]
+pub struct RgpUnknownChunk {
+    pub offset: usize,
+    #[allow(dead_code)]
+    pub header: RgpEntryHeader,
+    pub data: Vec<u8>,
+}
+
+#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u32)]
 pub enum SqttGfxipLevel {
     None = 0x0,
@@ -67,9 +200,28 @@ pub enum SqttGfxipLevel {
     GfxIp9 = 0x5,
     GfxIp10_1 = 0x7,
     GfxIp10_3 = 0x9,
+    GfxIp11 = 0xc,
+}
+
+/// Describes the trace in the SqttData chunk sharing the same chunk index.
+#[derive(Clone, Debug, PartialEq, Default, Pread, Pwrite, SizeWith)]
+pub struct RgpSqttDesc {
+    pub shader_engine_index: u32,
+    pub sqtt_version: u32,
+    pub instrumentation_spec_version: u16,
+    pub instrumentation_api_version: u16,
+    pub compute_unit_index: u32,
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpSqttData {
+    pub offset: u32,
+    pub size: u32,
 }
 
-#[derive(Clone, Debug, Pread)]
+pub const SQTT_DATA_HEADER_SIZE: usize = 8;
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
 pub struct RgpAsicInfo {
     pub flags: u64,
     pub trace_shader_core_clock: u64,
@@ -116,3 +268,326 @@ pub struct RgpAsicInfo {
     pub reserved1: [u8; 128],
     pub padding: [u8; 4],
 }
+
+#[derive(TryFromPrimitive, IntoPrimitive, Debug)]
+#[repr(u32)]
+pub enum SqttProfilingMode {
+    Present,
+    UserMarkers,
+    Index,
+    Tag,
+}
+
+/// Follows `sqtt_file_chunk_api_info` in Mesa's `ac_rgp.c`.
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpApiInfo {
+    pub api_type: u32,
+    pub api_version_major: u16,
+    pub api_version_minor: u16,
+    pub profiling_mode: u32,
+    pub reserved: u32,
+    /// Start/end markers, indices or tags depending on `profiling_mode`. The user markers are two 256 byte strings.
+    pub profiling_mode_data: [u8; 512],
+    pub instruction_trace_mode: u32,
+    pub reserved2: u32,
+    /// API PSO filter or shader engine mask depending on `instruction_trace_mode`.
+    pub instruction_trace_data: u64,
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpQueueEventTimings {
+    pub queue_info_table_record_count: u32,
+    pub queue_info_table_size: u32,
+    pub queue_event_table_record_count: u32,
+    pub queue_event_table_size: u32,
+}
+
+pub const QUEUE_EVENT_TIMINGS_HEADER_SIZE: usize = 16;
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpQueueInfoRecord {
+    pub queue_id: u64,
+    pub queue_context: u64,
+    pub queue_type: u8,
+    pub engine_type: u8,
+    pub reserved0: u16,
+    pub reserved1: u32,
+}
+
+#[derive(TryFromPrimitive, IntoPrimitive, Debug)]
+#[repr(u32)]
+pub enum SqttQueueEventType {
+    CmdbufSubmit,
+    SignalSemaphore,
+    WaitSemaphore,
+    Present,
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpQueueEventRecord {
+    pub event_type: u32,
+    pub sqtt_cb_id: u32,
+    pub frame_index: u64,
+    pub queue_info_index: u32,
+    pub submit_sub_index: u32,
+    pub api_id: u64,
+    pub cpu_timestamp: u64,
+    /// Start and end of the submission; only the first one is valid for other event types.
+    pub gpu_timestamps: [u64; 2],
+}
+
+/// Reads the queue info table and the queue event table that follow the QueueEventTimings header.
+pub fn parse_queue_event_timings(
+    chunk: &[u8],
+) -> Result<(Vec<RgpQueueInfoRecord>, Vec<RgpQueueEventRecord>), scroll::Error> {
+    let hdr: RgpQueueEventTimings = chunk.pread_with(0, LE)?;
+    let mut offset = QUEUE_EVENT_TIMINGS_HEADER_SIZE;
+    let queues = (0..hdr.queue_info_table_record_count)
+        .map(|_| chunk.gread_with(&mut offset, LE))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut offset = QUEUE_EVENT_TIMINGS_HEADER_SIZE + hdr.queue_info_table_size as usize;
+    let events = (0..hdr.queue_event_table_record_count)
+        .map(|_| chunk.gread_with(&mut offset, LE))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((queues, events))
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpClockCalibration {
+    pub cpu_timestamp: u64,
+    pub gpu_timestamp: u64,
+    pub reserved: u64,
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpCpuInfo {
+    pub vendor_id: [u8; 16],
+    pub processor_brand: [u8; 48],
+    pub reserved: [u32; 2],
+    pub cpu_timestamp_freq: u64,
+    /// In MHz.
+    pub clock_speed: u32,
+    pub num_logical_cores: u32,
+    pub num_physical_cores: u32,
+    /// In MiB.
+    pub system_ram_size: u32,
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpCodeObjectDatabase {
+    pub offset: u32,
+    pub flags: u32,
+    pub size: u32,
+    pub record_count: u32,
+}
+
+pub const CODE_OBJECT_DATABASE_HEADER_SIZE: usize = 16;
+
+/// Returns the code object binaries in the CodeObjectDatabase chunk.
+/// Each record is a 32-bit size followed by the (padded) ELF binary.
+pub fn parse_code_object_database(chunk: &[u8]) -> Result<Vec<&[u8]>, scroll::Error> {
+    let hdr: RgpCodeObjectDatabase = chunk.pread_with(0, LE)?;
+    let mut offset = CODE_OBJECT_DATABASE_HEADER_SIZE;
+    (0..hdr.record_count)
+        .map(|_| {
+            let size: u32 = chunk.gread_with(&mut offset, LE)?;
+            let record: &[u8] = chunk.gread_with(&mut offset, size as usize)?;
+            Ok(record)
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpCodeObjectLoaderEvents {
+    pub offset: u32,
+    pub flags: u32,
+    pub record_size: u32,
+    pub record_count: u32,
+}
+
+pub const CODE_OBJECT_LOADER_EVENTS_HEADER_SIZE: usize = 16;
+
+#[derive(TryFromPrimitive, IntoPrimitive, Debug)]
+#[repr(u32)]
+pub enum SqttLoaderEventType {
+    CodeObjectLoadToGpuMemory,
+    CodeObjectUnloadFromGpuMemory,
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpCodeObjectLoaderEventRecord {
+    pub loader_event_type: u32,
+    pub reserved: u32,
+    pub base_address: u64,
+    pub code_object_hash: [u64; 2],
+    /// On the CPU clock.
+    pub time_stamp: u64,
+}
+
+/// Checks that `record_count` records of `record_size` bytes fit in `len` bytes, and that each is large enough to hold
+/// a `T`. Writers may use larger records than we know about, but never smaller ones.
+fn check_record_table<T: SizeWith<Endian>>(
+    record_size: u32,
+    record_count: u32,
+    len: usize,
+) -> Result<(), scroll::Error> {
+    let record_size = record_size as usize;
+    if record_size < T::size_with(&LE) {
+        return Err(scroll::Error::BadInput {
+            size: record_size,
+            msg: "record size is smaller than the record",
+        });
+    }
+    let size = record_size.saturating_mul(record_count as usize);
+    if size > len {
+        return Err(scroll::Error::TooBig { size, len });
+    }
+    Ok(())
+}
+
+pub fn parse_code_object_loader_events(chunk: &[u8]) -> Result<Vec<RgpCodeObjectLoaderEventRecord>, scroll::Error> {
+    let hdr: RgpCodeObjectLoaderEvents = chunk.pread_with(0, LE)?;
+    check_record_table::<RgpCodeObjectLoaderEventRecord>(
+        hdr.record_size,
+        hdr.record_count,
+        chunk.len().saturating_sub(CODE_OBJECT_LOADER_EVENTS_HEADER_SIZE),
+    )?;
+    (0..hdr.record_count as usize)
+        .map(|i| chunk.pread_with(CODE_OBJECT_LOADER_EVENTS_HEADER_SIZE + i * hdr.record_size as usize, LE))
+        .collect()
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpPsoCorrelation {
+    pub offset: u32,
+    pub flags: u32,
+    pub record_size: u32,
+    pub record_count: u32,
+}
+
+pub const PSO_CORRELATION_HEADER_SIZE: usize = 16;
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpPsoCorrelationRecord {
+    pub api_pso_hash: u64,
+    /// The internal pipeline hash, matching the code objects of the pipeline.
+    pub pipeline_hash: [u64; 2],
+    pub api_level_obj_name: [u8; 64],
+}
+
+pub fn parse_pso_correlation(chunk: &[u8]) -> Result<Vec<RgpPsoCorrelationRecord>, scroll::Error> {
+    let hdr: RgpPsoCorrelation = chunk.pread_with(0, LE)?;
+    check_record_table::<RgpPsoCorrelationRecord>(
+        hdr.record_size,
+        hdr.record_count,
+        chunk.len().saturating_sub(PSO_CORRELATION_HEADER_SIZE),
+    )?;
+    (0..hdr.record_count as usize)
+        .map(|i| chunk.pread_with(PSO_CORRELATION_HEADER_SIZE + i * hdr.record_size as usize, LE))
+        .collect()
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpSpmDb {
+    pub flags: u32,
+    /// Size of the chunk preamble including the chunk header, i.e. the offset of the timestamps.
+    pub preamble_size: u32,
+    pub num_timestamps: u32,
+    pub num_spm_counter_info: u32,
+    pub spm_counter_info_size: u32,
+    pub sample_interval: u32,
+}
+
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpSpmCounterInfo {
+    pub block: u32,
+    pub instance: u32,
+    /// Offset of the samples from the start of the chunk (including the chunk header).
+    pub data_offset: u32,
+    pub event_index: u32,
+}
+
+pub struct RgpSpmCounter {
+    pub info: RgpSpmCounterInfo,
+    pub values: Vec<u16>,
+}
+
+pub struct RgpSpmTrace {
+    pub header: RgpSpmDb,
+    /// GPU timestamps of each sample.
+    pub timestamps: Vec<u64>,
+    pub counters: Vec<RgpSpmCounter>,
+}
+
+/// Reads the sample timestamps, the counter info table and each counter's samples from a SpmDb chunk.
+pub fn parse_spm_db(chunk: &[u8]) -> Result<RgpSpmTrace, scroll::Error> {
+    let header: RgpSpmDb = chunk.pread_with(0, LE)?;
+    // Offsets in the chunk are relative to the chunk header, which is not part of `chunk`.
+    let mut offset = (header.preamble_size as usize).saturating_sub(ENTRY_HEADER_SIZE);
+    let timestamps = (0..header.num_timestamps)
+        .map(|_| chunk.gread_with(&mut offset, LE))
+        .collect::<Result<Vec<u64>, _>>()?;
+    check_record_table::<RgpSpmCounterInfo>(
+        header.spm_counter_info_size,
+        header.num_spm_counter_info,
+        chunk.len().saturating_sub(offset),
+    )?;
+    let infos = (0..header.num_spm_counter_info as usize)
+        .map(|i| chunk.pread_with::<RgpSpmCounterInfo>(offset + i * header.spm_counter_info_size as usize, LE))
+        .collect::<Result<Vec<_>, _>>()?;
+    let counters = infos
+        .into_iter()
+        .map(|info| {
+            let mut offset = (info.data_offset as usize).saturating_sub(ENTRY_HEADER_SIZE);
+            let values = (0..header.num_timestamps)
+                .map(|_| chunk.gread_with(&mut offset, LE))
+                .collect::<Result<Vec<u16>, _>>()?;
+            Ok(RgpSpmCounter { info, values })
+        })
+        .collect::<Result<Vec<_>, scroll::Error>>()?;
+    Ok(RgpSpmTrace {
+        header,
+        timestamps,
+        counters,
+    })
+}
+
+/// Only the leading version fields of the instrumentation table are decoded.
+#[derive(Clone, Debug, Pread, Pwrite, SizeWith)]
+pub struct RgpInstrumentationTable {
+    pub instrumentation_spec_version: u16,
+    pub instrumentation_api_version: u16,
+}
+
+/// Appends the serialized `value` to `buf`.
+pub fn push_struct<T>(buf: &mut Vec<u8>, value: T) -> Result<(), scroll::Error>
+where
+    T: SizeWith<Endian> + TryIntoCtx<Endian, Error = scroll::Error>,
+{
+    let offset = buf.len();
+    buf.resize(offset + T::size_with(&LE), 0);
+    buf.pwrite_with(value, offset, LE)?;
+    Ok(())
+}
+
+/// The `offset` field is left for `RgpWriter` to fill in.
+pub fn write_pso_correlation(records: &[RgpPsoCorrelationRecord]) -> Result<Vec<u8>, scroll::Error> {
+    let mut buf = vec![];
+    let hdr = RgpPsoCorrelation {
+        offset: 0,
+        flags: 0,
+        record_size: RgpPsoCorrelationRecord::size_with(&LE) as u32,
+        record_count: records.len() as u32,
+    };
+    push_struct(&mut buf, hdr)?;
+    for record in records {
+        push_struct(&mut buf, record.clone())?;
+    }
+    Ok(buf)
+}
+
+/// Decodes a fixed-size, NUL-padded string field.
+pub fn c_str(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
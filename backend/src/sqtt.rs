@@ -4,16 +4,21 @@ use std::num::NonZeroU8;
 use anyhow::{bail, Result};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use paste::paste;
-use tracing::warn;
+use thiserror::Error;
+use tracing::{debug, warn};
 
-use crate::merge::{MergedIterator, MergedIteratorItem};
-use crate::rgp::{RgpAsicInfo, SqttGfxipLevel};
+use crate::rgp::{RgpAsicInfo, RgpSqttDesc, SqttGfxipLevel};
 
 macro_rules! gen_parser_inner {
+    (
+        $self:ident $last_consume:ident $reader:ident $timestamp:ident [-] $field:ident: $ty:ty
+    ) => {
+        $self.$field.push(<$ty>::default());
+    };
     (
         $self:ident $last_consume:ident $reader:ident $timestamp:ident [$top:literal:$bottom:literal] dt: $ty:ty
     ) => {
-        if $top - $last_consume > 60 {
+        if $top + 1 - $last_consume > 60 {
             $reader.consume($bottom - $last_consume);
             $last_consume = $bottom;
         }
@@ -22,7 +27,7 @@ macro_rules! gen_parser_inner {
     (
         $self:ident $last_consume:ident $reader:ident $timestamp:ident [$top:literal:$bottom:literal] $field:ident: $ty:ty
     ) => {
-        if $top - $last_consume > 60 {
+        if $top + 1 - $last_consume > 60 {
             $reader.consume($bottom - $last_consume);
             $last_consume = $bottom;
         }
@@ -31,6 +36,33 @@ macro_rules! gen_parser_inner {
     };
 }
 
+/// Generates a parse function for a packet layout. Every column of the packet has to be listed; columns marked `[-]`
+/// don't exist in the layout and are filled with zeroes. A field ending more than 60 bits past the reader's position
+/// advances the reader to its bottom bit, so no later field may start below that. Listing fields in order of the
+/// bottom bit always satisfies this.
+macro_rules! gen_layout {
+    (
+        $(impl $pkt:ident::$parse:ident {
+            $([$($range:tt)+] $field:ident: $ty:ty,)+
+        })+
+    ) => {
+        $(
+            impl $pkt {
+                #[allow(unused_variables, unused_assignments, unused_mut)]
+                fn $parse<R: SqttReader>(&mut self, reader: &mut R, seq: u32, timestamp: &mut u64) -> Option<()> {
+                    let mut last_consume = 0;
+                    $(
+                        gen_parser_inner!(self last_consume reader timestamp [$($range)+] $field: $ty);
+                    )+
+                    self.seq.push(seq);
+                    self.timestamp.push(*timestamp);
+                    Some(())
+                }
+            }
+        )+
+    };
+}
+
 macro_rules! gen_parser {
     (
         $(packet $pkt:ident {
@@ -38,30 +70,27 @@ macro_rules! gen_parser {
         })+
     ) => {
         $(
-            #[derive(Default)]
+            #[derive(Default, Debug, PartialEq)]
+            #[allow(dead_code)]
             pub struct $pkt {
                 pub seq: Vec<u32>,
                 pub timestamp: Vec<u64>,
                 $(pub $field: Vec<$ty>),+
             }
 
-            impl $pkt {
-                #[allow(unused_variables, unused_assignments)]
-                fn parse(&mut self, reader: &mut BitReader, seq: u32, timestamp: &mut u64) -> Option<()> {
-                    let mut last_consume = 0;
-                    $(
-                        gen_parser_inner!(self last_consume reader timestamp [$top:$bottom] $field: $ty);
-                    )+
-                    self.seq.push(seq);
-                    self.timestamp.push(*timestamp);
-                    Some(())
+            gen_layout! {
+                impl $pkt::parse {
+                    $([$top:$bottom] $field: $ty,)+
                 }
             }
         )+
 
         paste! {
-            #[derive(Default)]
+            #[derive(Default, Debug, PartialEq)]
             pub struct SqttChunk {
+                pub desc: RgpSqttDesc,
+                /// Regions skipped while resynchronizing after undecodable data.
+                pub gaps: Vec<DecodeGap>,
                 $(pub [<$pkt:snake>]: $pkt),+
             }
         }
@@ -115,8 +144,9 @@ gen_parser! {
         [15:14] a0: u8,
         [17:16] a1: u8,
         [19:18] initiator_type: u8,
+        // Intentionally overlaps `val`, which keeps all of the payload bits; `id` only uses their bottom 20 bits.
+        [46:44] context: u8,
         [52:20] val: u32,
-        // [46:44] context: u8,
     }
 
     packet RegWrite {
@@ -211,35 +241,178 @@ gen_parser! {
     }
 }
 
+/// What an `Initiator` packet reports the start of.
+#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum SqttInitiatorType {
+    Event = 0,
+    Draw = 1,
+    Dispatch = 2,
+}
+
+impl Initiator {
+    /// `None` for the undocumented initiator type 3.
+    pub fn kind(&self, i: usize) -> Option<SqttInitiatorType> {
+        self.initiator_type[i].try_into().ok()
+    }
+
+    /// The event, draw or dispatch id.
+    pub fn id(&self, i: usize) -> u32 {
+        self.val[i] & 0xfffff
+    }
+}
+
+// gfx9 tokens that carry the same information as a gfx10 packet are decoded into the same columns. gfx9 has CUs
+// instead of WGPs, so `wgp` holds the CU index. Columns whose gfx10 meaning is unknown (`a0`, `a1`, `b0`) are left
+// empty rather than filled with gfx9 bits that may mean something else.
+//
+// These layouts, like the token lengths in `sqtt_packet_length_gfx9`, are not taken from a published document and
+// have not been checked against gfx9 captures; `gfx9_tokens` only pins down what we decode today.
+gen_layout! {
+    impl SetPc::parse_gfx9 {
+        [ 5: 4] dt: u8,
+        [11: 8] wave: u8,
+        [63:16] pc: u64,
+        [-] reserved0: u8,
+    }
+
+    impl LongTimestamp::parse_gfx9 {
+        [-] ty: u8,
+        [63:16] timestamp_value: u64,
+    }
+
+    impl EventA::parse_gfx9 {
+        [ 5: 4] dt: u8,
+        [-] b0: u8,
+        [ 7: 6] selector: u8,
+        [10: 8] stage: u8,
+        [-] a0: u8,
+    }
+
+    impl RegWrite::parse_gfx9 {
+        [ 5: 4] dt: u8,
+        [-] a0: u8,
+        [-] a1: u8,
+        [-] b0: u8,
+        [15:15] is_write: u8,
+        [31:16] reg: u16,
+        [63:32] val: u32,
+    }
+
+    // WaveAlloc and WaveEnd have no room for the SH index, so leave it out here too to keep slots comparable.
+    impl WaveStart::parse_gfx9 {
+        [ 5: 4] dt: u8,
+        [ 7: 6] simd: u8,
+        [11: 8] wgp: u8,
+        [15:12] wave: u8,
+        [-] sh: u8,
+        [19:17] stage: u8,
+        [31:25] threads: u8,
+    }
+
+    impl WaveAlloc::parse_gfx9 {
+        [ 5: 4] dt: u8,
+        [ 7: 6] simd: u8,
+        [11: 8] wgp: u8,
+        [15:12] wave: u8,
+        [-] sh: u8,
+    }
+
+    impl WaveEnd::parse_gfx9 {
+        [ 5: 4] dt: u8,
+        [ 7: 6] simd: u8,
+        [11: 8] wgp: u8,
+        [15:12] wave: u8,
+        [-] sh: u8,
+    }
+
+    impl GenericInst::parse_gfx9 {
+        [ 5: 4] dt: u8,
+        [-] b0: u8,
+        [-] a0: u8,
+        [15:12] insn: u8,
+    }
+
+    impl ShaderData::parse_gfx9 {
+        [ 5: 4] dt: u8,
+        [ 7: 6] simd: u8,
+        [11: 8] wave: u8,
+        [-] sh: u8,
+        [-] wgp: u8,
+        [47:16] val: u32,
+    }
+}
+
+/// Reads fields relative to the current position in a SQTT stream.
+trait SqttReader: Clone {
+    /// Reads `width` bits starting `lsb` bits after the current position. `lsb + width` must be at most 60.
+    fn bits(&self, lsb: usize, width: usize) -> Option<u64>;
+    /// Advances the position by `bits`, or returns `None` if that would go past the end of the input.
+    fn consume(&mut self, bits: usize) -> Option<()>;
+    /// The number of bits consumed since the start of the input.
+    fn position(&self) -> usize;
+}
+
+/// The reader used for decoding. The `checked` feature swaps `BitReader` for the slower `CheckedBitReader`.
+#[cfg(not(feature = "checked"))]
+type Reader<'a> = BitReader<'a>;
+#[cfg(feature = "checked")]
+type Reader<'a> = CheckedBitReader<'a>;
+
 #[derive(Clone)]
+#[cfg_attr(feature = "checked", allow(dead_code))]
 struct BitReader<'a> {
     input: &'a [u8],
+    len: usize,
     bits: u64,
     bits_consumed: usize,
 }
 
+#[cfg_attr(feature = "checked", allow(dead_code))]
 impl<'a> BitReader<'a> {
     pub fn new(input: &'a [u8]) -> BitReader<'a> {
-        if input.len() < 8 {
-            unimplemented!("Short input initialization not implemented");
-        }
-
+        // Decoding starts after the first 8 bytes of the stream; inputs shorter than that decode to nothing.
         let mut ret = Self {
             input,
+            len: input.len(),
             bits: 0,
-            bits_consumed: 64,
+            bits_consumed: cmp::min(64, input.len() * 8),
         };
         ret.refill();
 
         ret
     }
 
+    fn refill(&mut self) {
+        // We can consume a maximum of 12B at once + 4 bit leftover (1B) + 8B read
+        if self.input.len() < 29 {
+            return self.refill_slow();
+        }
+        self.input = unsafe { self.input.get_unchecked(self.bits_consumed / 8..) };
+        self.bits_consumed %= 8;
+        self.bits = u64::from_le_bytes(unsafe { self.input.get_unchecked(..8) }.try_into().unwrap());
+    }
+
+    fn refill_slow(&mut self) {
+        // Keep the last 8 bytes buffered so that the tail of the stream can still be read.
+        let advance = cmp::min(self.bits_consumed / 8, self.input.len().saturating_sub(8));
+        self.input = &self.input[advance..];
+        self.bits_consumed -= advance * 8;
+        let mut bytes = [0; 8];
+        let len = cmp::min(8, self.input.len());
+        bytes[..len].copy_from_slice(&self.input[..len]);
+        self.bits = u64::from_le_bytes(bytes);
+    }
+}
+
+impl SqttReader for BitReader<'_> {
     #[inline]
-    pub fn bits(&self, lsb: usize, width: usize) -> Option<u64> {
+    fn bits(&self, lsb: usize, width: usize) -> Option<u64> {
         // We maintain an invariant of bits_consumed <= 4 after refill.
         assert!(lsb + width <= 60);
 
-        if lsb + width > 64 - self.bits_consumed {
+        // Only the last 8 bytes of the input are buffered in `bits`, or fewer if the input is shorter.
+        if lsb + width > cmp::min(64, self.input.len() * 8) - self.bits_consumed {
             return None;
         }
 
@@ -247,7 +420,7 @@ impl<'a> BitReader<'a> {
     }
 
     #[inline]
-    pub fn consume(&mut self, bits: usize) -> Option<()> {
+    fn consume(&mut self, bits: usize) -> Option<()> {
         if bits + self.bits_consumed > self.input.len() * 8 {
             return None;
         }
@@ -256,27 +429,131 @@ impl<'a> BitReader<'a> {
         Some(())
     }
 
-    fn refill(&mut self) {
-        // We can consume a maximum of 12B at once + 4 bit leftover (1B) + 8B read
-        if self.input.len() < 29 {
-            return self.refill_slow();
+    /// The number of bits consumed since the start of the input.
+    fn position(&self) -> usize {
+        (self.len - self.input.len()) * 8 + self.bits_consumed
+    }
+}
+
+/// A reader that checks every access against the input, as a reference for `BitReader`.
+#[derive(Clone)]
+#[cfg_attr(not(any(test, feature = "checked")), allow(dead_code))]
+struct CheckedBitReader<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+#[cfg_attr(not(any(test, feature = "checked")), allow(dead_code))]
+impl<'a> CheckedBitReader<'a> {
+    pub fn new(input: &'a [u8]) -> CheckedBitReader<'a> {
+        // Same starting point as `BitReader`.
+        CheckedBitReader {
+            input,
+            position: cmp::min(64, input.len() * 8),
         }
-        self.input = unsafe { self.input.get_unchecked(self.bits_consumed / 8..) };
-        self.bits_consumed %= 8;
-        self.bits = u64::from_le_bytes(unsafe { self.input.get_unchecked(..8) }.try_into().unwrap());
     }
+}
 
-    fn refill_slow(&mut self) {
-        let advance = cmp::min(self.bits_consumed / 8, self.input.len() - 8);
-        self.input = &self.input[advance..];
-        self.bits_consumed -= advance * 8;
-        self.bits = u64::from_le_bytes(self.input[..8].try_into().unwrap());
+impl SqttReader for CheckedBitReader<'_> {
+    fn bits(&self, lsb: usize, width: usize) -> Option<u64> {
+        assert!(lsb + width <= 60);
+
+        let start = self.position + lsb;
+        if start + width > self.input.len() * 8 {
+            return None;
+        }
+        let mut value = 0;
+        for i in 0..width {
+            let bit = start + i;
+            value |= (((self.input[bit / 8] >> (bit % 8)) & 1) as u64) << i;
+        }
+        Some(value)
+    }
+
+    fn consume(&mut self, bits: usize) -> Option<()> {
+        if self.position + bits > self.input.len() * 8 {
+            return None;
+        }
+        self.position += bits;
+        Some(())
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SqttError {
+    #[error("No SQTT decoder for {0:?}; use --best-effort to decode it as gfx10")]
+    UnsupportedGfxip(SqttGfxipLevel),
+    #[error("Unknown gfxip level {0:#x}; use --best-effort to decode it as gfx10")]
+    UnknownGfxip(u32),
+}
+
+/// The SQTT packet encodings we can decode.
+///
+/// gfx11 has no encoding yet: its packet table and layouts differ from gfx10.3 and still have to be derived from RDNA3
+/// captures.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SqttEncoding {
+    Gfx9,
+    Gfx10,
+    Gfx10_3,
+}
+
+/// How to decode a SQTT stream.
+#[derive(Clone, Copy, Debug)]
+pub struct SqttOptions {
+    pub encoding: SqttEncoding,
+    /// Skip over undecodable data instead of failing, see `DecodeGap`.
+    pub resync: bool,
+}
+
+impl SqttEncoding {
+    /// Picks the encoding for the capture's gfxip level. With `best_effort`, levels without a decoder are decoded as
+    /// gfx10 instead of failing.
+    pub fn new(asic_info: &RgpAsicInfo, best_effort: bool) -> Result<Self, SqttError> {
+        let err = match SqttGfxipLevel::try_from(asic_info.gfxip_level) {
+            Ok(SqttGfxipLevel::GfxIp9) => return Ok(SqttEncoding::Gfx9),
+            Ok(SqttGfxipLevel::GfxIp10_1) => return Ok(SqttEncoding::Gfx10),
+            Ok(SqttGfxipLevel::GfxIp10_3) => return Ok(SqttEncoding::Gfx10_3),
+            Ok(level) => SqttError::UnsupportedGfxip(level),
+            Err(_) => SqttError::UnknownGfxip(asic_info.gfxip_level),
+        };
+        if !best_effort {
+            return Err(err);
+        }
+        warn!("{}, decoding as gfx10 anyway", err);
+        Ok(SqttEncoding::Gfx10)
+    }
+
+    fn packet_length(self, selector: u8) -> Option<NonZeroU8> {
+        match self {
+            SqttEncoding::Gfx9 => sqtt_packet_length_gfx9(selector),
+            SqttEncoding::Gfx10 | SqttEncoding::Gfx10_3 => sqtt_packet_length(selector, self),
+        }
+    }
+
+    /// Whether `selector` starts a packet that sets the timestamp.
+    fn is_long_timestamp(self, selector: u8) -> bool {
+        match self {
+            SqttEncoding::Gfx9 => selector % 16 == 0x1,
+            SqttEncoding::Gfx10 | SqttEncoding::Gfx10_3 => selector % 128 == 0x1,
+        }
+    }
+
+    fn decoder<R: SqttReader>(self) -> DecodeFn<R> {
+        match self {
+            SqttEncoding::Gfx9 => decode_packet_gfx9,
+            SqttEncoding::Gfx10 | SqttEncoding::Gfx10_3 => decode_packet,
+        }
     }
 }
 
-/// The length in bits of a SQTT packet.
+/// The length in bits of a gfx10 SQTT packet.
 /// `selector` is the bottom 8 bits of the packet.
-fn sqtt_packet_length(selector: u8, asic_info: &RgpAsicInfo) -> Option<NonZeroU8> {
+fn sqtt_packet_length(selector: u8, encoding: SqttEncoding) -> Option<NonZeroU8> {
     Some(
         NonZeroU8::new(match selector % 8 {
             2 => 20,
@@ -295,7 +572,7 @@ fn sqtt_packet_length(selector: u8, asic_info: &RgpAsicInfo) -> Option<NonZeroU8
                     _ => return None,
                 },
                 4 => {
-                    if asic_info.gfxip_level == SqttGfxipLevel::GfxIp10_3.into() {
+                    if encoding == SqttEncoding::Gfx10_3 {
                         24
                     } else {
                         28
@@ -318,22 +595,212 @@ fn sqtt_packet_length(selector: u8, asic_info: &RgpAsicInfo) -> Option<NonZeroU8
     )
 }
 
-fn build_packet_length_table(asic_info: &RgpAsicInfo) -> [Option<NonZeroU8>; 256] {
+/// The length in bits of a gfx9 SQTT token. The token type is the bottom 4 bits, numbered like the token bits of
+/// `SQ_THREAD_TRACE_TOKEN_MASK`. The lengths have no published source and are unverified, see the gfx9 layouts.
+fn sqtt_packet_length_gfx9(selector: u8) -> Option<NonZeroU8> {
+    NonZeroU8::new(match selector % 16 {
+        0x0 => 16, // Misc
+        0x1 => 64, // Timestamp
+        0x2 => 64, // Reg
+        0x3 => 32, // WaveStart
+        0x4 => 16, // WaveAlloc
+        0x5 => 48, // RegCsPriv
+        0x6 => 16, // WaveEnd
+        0x7 => 16, // Event
+        0x8 => 16, // EventCs
+        0x9 => 16, // EventGfx1
+        0xa => 16, // Inst
+        0xb => 64, // InstPc
+        0xc => 48, // InstUserData
+        0xd => 32, // Issue
+        0xe => 64, // Perf
+        0xf => 48, // RegCs
+        _ => unreachable!(),
+    })
+}
+
+fn build_packet_length_table(encoding: SqttEncoding) -> [Option<NonZeroU8>; 256] {
     (0..=255)
-        .map(|i| sqtt_packet_length(i, asic_info))
+        .map(|i| encoding.packet_length(i))
         .collect::<Vec<_>>()
         .try_into()
         .unwrap()
 }
 
-pub fn parse_sqtt(i: &[u8], asic_info: &RgpAsicInfo) -> Result<SqttChunk> {
-    let mut reader = BitReader::new(i);
+type DecodeFn<R> = fn(&mut SqttChunk, u8, &mut R, u32, &mut u64) -> Option<()>;
+
+/// Decodes a gfx10 packet into its column. Returns `None` if the packet is truncated.
+fn decode_packet<R: SqttReader>(
+    result: &mut SqttChunk,
+    selector: u8,
+    reader: &mut R,
+    seq: u32,
+    timestamp: &mut u64,
+) -> Option<()> {
+    match selector % 8 {
+        2 => result.generic_inst.parse(reader, seq, timestamp),
+        3 => result.valu_inst.parse(reader, seq, timestamp),
+        _ => match selector % 16 {
+            1 => match (selector / 16) % 8 {
+                0 => {
+                    result.long_timestamp.parse(reader, seq, timestamp)?;
+                    if *result.long_timestamp.ty.last().unwrap() == 1 {
+                        *timestamp += result.long_timestamp.timestamp_value.last().unwrap();
+                    }
+                    Some(())
+                }
+                2 => result.set_pc.parse(reader, seq, timestamp),
+                3 => result.packet0x31.parse(reader, seq, timestamp),
+                4 => result.packet0x41.parse(reader, seq, timestamp),
+                5 => result.packet0x51.parse(reader, seq, timestamp),
+                6 => match selector / 16 {
+                    6 => result.event_a.parse(reader, seq, timestamp),
+                    14 => result.event_b.parse(reader, seq, timestamp),
+                    _ => unreachable!(),
+                },
+                7 => result.initiator.parse(reader, seq, timestamp),
+                _ => Some(()),
+            },
+            4 => result.immediate.parse(reader, seq, timestamp),
+            5 => match selector % 32 {
+                0x5 => result.wave_alloc.parse(reader, seq, timestamp),
+                0x15 => result.wave_end.parse(reader, seq, timestamp),
+                _ => unreachable!(),
+            },
+            6 => match selector % 32 {
+                0x6 => result.shader_data.parse(reader, seq, timestamp),
+                0x16 => result.shader_data_imm.parse(reader, seq, timestamp),
+                _ => unreachable!(),
+            },
+            8 => {
+                result.short_timestamp.parse(reader, seq, timestamp)?;
+                *timestamp += *result.short_timestamp.dt_4.last().unwrap() as u64 + 4;
+                Some(())
+            }
+            9 => result.reg_write.parse(reader, seq, timestamp),
+            12 => result.wave_start.parse(reader, seq, timestamp),
+            13 => result.immediate_one.parse(reader, seq, timestamp),
+            14 => result.alu_exec.parse(reader, seq, timestamp),
+            15 => result.vmem_exec.parse(reader, seq, timestamp),
+            _ => Some(()),
+        },
+    }
+}
+
+/// Decodes a gfx9 token into its column. Returns `None` if the token is truncated.
+fn decode_packet_gfx9<R: SqttReader>(
+    result: &mut SqttChunk,
+    selector: u8,
+    reader: &mut R,
+    seq: u32,
+    timestamp: &mut u64,
+) -> Option<()> {
+    match selector % 16 {
+        0x0 => {
+            // Misc tokens only advance the clock.
+            *timestamp += reader.bits(4, 8)?;
+            Some(())
+        }
+        0x1 => {
+            result.long_timestamp.parse_gfx9(reader, seq, timestamp)?;
+            // Unlike gfx10, timestamp tokens hold the absolute time.
+            *timestamp = *result.long_timestamp.timestamp_value.last().unwrap();
+            Some(())
+        }
+        0x2 => result.reg_write.parse_gfx9(reader, seq, timestamp),
+        0x3 => result.wave_start.parse_gfx9(reader, seq, timestamp),
+        0x4 => result.wave_alloc.parse_gfx9(reader, seq, timestamp),
+        0x6 => result.wave_end.parse_gfx9(reader, seq, timestamp),
+        0x7 => result.event_a.parse_gfx9(reader, seq, timestamp),
+        0xa => result.generic_inst.parse_gfx9(reader, seq, timestamp),
+        0xb => result.set_pc.parse_gfx9(reader, seq, timestamp),
+        0xc => result.shader_data.parse_gfx9(reader, seq, timestamp),
+        // Tokens without a gfx10 counterpart still advance the clock.
+        _ => {
+            *timestamp += reader.bits(4, 2)?;
+            Some(())
+        }
+    }
+}
+
+/// A region of the SQTT stream that couldn't be decoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeGap {
+    pub bit_offset: usize,
+    pub bit_len: usize,
+    /// The timestamp before the gap. Time deltas in the gap are lost, so later timestamps may be early.
+    pub timestamp: u64,
+}
+
+/// The number of packets that have to decode after a long timestamp for it to be accepted as a resync point.
+const RESYNC_RUN_LENGTH: usize = 16;
+
+/// Whether `reader` is at a long timestamp followed by a run of packets of known types.
+fn is_resync_point<R: SqttReader>(
+    reader: &R,
+    pkt_len_table: &[Option<NonZeroU8>; 256],
+    encoding: SqttEncoding,
+) -> bool {
+    let mut reader = reader.clone();
+    match reader.bits(0, 8) {
+        Some(selector) if encoding.is_long_timestamp(selector as u8) => {}
+        _ => return false,
+    }
+    for _ in 0..RESYNC_RUN_LENGTH {
+        let Some(selector) = reader.bits(0, 8) else {
+            return true;
+        };
+        let Some(pkt_len) = pkt_len_table[selector as usize] else {
+            return false;
+        };
+        if reader.consume(pkt_len.get() as usize).is_none() {
+            return true;
+        }
+    }
+    true
+}
+
+/// Location and time of a decoded packet in the SQTT stream.
+pub struct PacketSpan {
+    pub bit_offset: usize,
+    pub bit_len: usize,
+    /// The timestamp before the packet was decoded.
+    pub start_timestamp: u64,
+    /// The timestamp after the packet was decoded.
+    pub timestamp: u64,
+}
+
+pub fn parse_sqtt(i: &[u8], desc: RgpSqttDesc, options: SqttOptions) -> Result<SqttChunk> {
+    parse_sqtt_with(i, desc, options, |_| {})
+}
+
+/// Like `parse_sqtt`, but calls `on_packet` for every decoded packet.
+pub fn parse_sqtt_with(
+    i: &[u8],
+    desc: RgpSqttDesc,
+    options: SqttOptions,
+    on_packet: impl FnMut(PacketSpan),
+) -> Result<SqttChunk> {
+    decode(Reader::new(i), i.len(), desc, options, on_packet)
+}
+
+fn decode<R: SqttReader>(
+    mut reader: R,
+    len: usize,
+    desc: RgpSqttDesc,
+    options: SqttOptions,
+    mut on_packet: impl FnMut(PacketSpan),
+) -> Result<SqttChunk> {
     let mut seq = 0;
     let mut timestamp = 0;
 
-    let pkt_len_table = build_packet_length_table(asic_info);
+    let pkt_len_table = build_packet_length_table(options.encoding);
+    let decode_packet = options.encoding.decoder();
 
-    let mut result = SqttChunk::default();
+    let mut result = SqttChunk {
+        desc,
+        ..Default::default()
+    };
 
     loop {
         let selector = reader.bits(0, 8);
@@ -342,97 +809,438 @@ pub fn parse_sqtt(i: &[u8], asic_info: &RgpAsicInfo) -> Result<SqttChunk> {
             match selector {
                 None => break,    // Reached end of stream
                 Some(0) => break, // Reached end of stream with final 4-byte padding,
-                Some(x) => bail!("Unknown packet type {}", x),
+                Some(x) if !options.resync => bail!("Unknown packet type {}", x),
+                Some(_) => {
+                    result.gaps.push(DecodeGap {
+                        bit_offset: reader.position(),
+                        bit_len: 4,
+                        timestamp,
+                    });
+                    break;
+                }
             }
         }
         let selector = selector.unwrap();
 
         let pkt_len = pkt_len_table[selector as usize];
         if pkt_len.is_none() {
-            bail!("Unknown packet type {}", selector);
+            if !options.resync {
+                bail!("Unknown packet type {}", selector);
+            }
+            // Packets are nibble aligned, so scan forward one nibble at a time.
+            let bit_offset = reader.position();
+            while reader.consume(4).is_some() && !is_resync_point(&reader, &pkt_len_table, options.encoding) {}
+            let gap = DecodeGap {
+                bit_offset,
+                bit_len: cmp::min(reader.position(), len * 8) - bit_offset,
+                timestamp,
+            };
+            warn!(
+                "unknown packet type {} at bit {}, skipped {} bits",
+                selector, gap.bit_offset, gap.bit_len
+            );
+            result.gaps.push(gap);
+            continue;
         }
         let pkt_len = pkt_len.unwrap().get() as usize;
 
+        let bit_offset = reader.position();
+        let start_timestamp = timestamp;
         let mut subreader = reader.clone();
         let advance = reader.consume(pkt_len);
 
         // TODO: this part should be error free (assuming advance.is_some())
-        let parse_result = match selector % 8 {
-            2 => result.generic_inst.parse(&mut subreader, seq, &mut timestamp),
-            3 => result.valu_inst.parse(&mut subreader, seq, &mut timestamp),
-            _ => match selector % 16 {
-                1 => match (selector / 16) % 8 {
-                    0 => {
-                        let ret = result.long_timestamp.parse(&mut subreader, seq, &mut timestamp);
-                        if *result.long_timestamp.ty.last().unwrap() == 1 {
-                            timestamp += result.long_timestamp.timestamp_value.last().unwrap();
-                        }
-                        ret
-                    }
-                    2 => result.set_pc.parse(&mut subreader, seq, &mut timestamp),
-                    3 => result.packet0x31.parse(&mut subreader, seq, &mut timestamp),
-                    4 => result.packet0x41.parse(&mut subreader, seq, &mut timestamp),
-                    5 => result.packet0x51.parse(&mut subreader, seq, &mut timestamp),
-                    6 => match selector / 16 {
-                        6 => result.event_a.parse(&mut subreader, seq, &mut timestamp),
-                        14 => result.event_b.parse(&mut subreader, seq, &mut timestamp),
-                        _ => unreachable!(),
-                    },
-                    7 => result.initiator.parse(&mut subreader, seq, &mut timestamp),
-                    _ => Some(()),
-                },
-                4 => result.immediate.parse(&mut subreader, seq, &mut timestamp),
-                5 => match selector % 32 {
-                    0x5 => result.wave_alloc.parse(&mut subreader, seq, &mut timestamp),
-                    0x15 => result.wave_end.parse(&mut subreader, seq, &mut timestamp),
-                    _ => unreachable!(),
-                },
-                6 => match selector % 32 {
-                    0x6 => result.shader_data.parse(&mut subreader, seq, &mut timestamp),
-                    0x16 => result.shader_data_imm.parse(&mut subreader, seq, &mut timestamp),
-                    _ => unreachable!(),
-                },
-                8 => {
-                    let ret = result.short_timestamp.parse(&mut subreader, seq, &mut timestamp);
-                    timestamp += *result.short_timestamp.dt_4.last().unwrap() as u64 + 4;
-                    ret
-                }
-                9 => result.reg_write.parse(&mut subreader, seq, &mut timestamp),
-                12 => result.wave_start.parse(&mut subreader, seq, &mut timestamp),
-                13 => result.immediate_one.parse(&mut subreader, seq, &mut timestamp),
-                14 => result.alu_exec.parse(&mut subreader, seq, &mut timestamp),
-                15 => result.vmem_exec.parse(&mut subreader, seq, &mut timestamp),
-                _ => Some(()),
-            },
-        };
+        let parse_result = decode_packet(&mut result, selector as u8, &mut subreader, seq, &mut timestamp);
 
         if parse_result.is_none() || advance.is_none() {
             warn!("Unexpected EOF during parsing, truncated capture?");
             break;
         }
+        on_packet(PacketSpan {
+            bit_offset,
+            bit_len: pkt_len,
+            start_timestamp,
+            timestamp,
+        });
         seq += 1;
     }
-    dbg!(result.generic_inst.seq.len());
-    dbg!(result.valu_inst.seq.len());
-    dbg!(result.event_a.seq.len());
-    dbg!(result.event_b.seq.len());
-    dbg!(result.immediate.seq.len());
-    dbg!(result.immediate_one.seq.len());
-    dbg!(result.initiator.seq.len());
-    dbg!(result.short_timestamp.seq.len());
-    dbg!(result.long_timestamp.seq.len());
-    dbg!(result.set_pc.seq.len());
-    dbg!(result.packet0x31.seq.len());
-    dbg!(result.packet0x41.seq.len());
-    dbg!(result.packet0x51.seq.len());
-    dbg!(result.shader_data.seq.len());
-    dbg!(result.shader_data_imm.seq.len());
-    dbg!(result.alu_exec.seq.len());
-    dbg!(result.vmem_exec.seq.len());
-    dbg!(result.reg_write.seq.len());
-    dbg!(result.wave_start.seq.len());
-    dbg!(result.wave_alloc.seq.len());
-    dbg!(result.wave_end.seq.len());
+    debug!("decoded {} packets, skipped {} gaps", seq, result.gaps.len());
 
     Ok(result)
 }
+
+/// Cuts a SQTT stream down to the packets whose timestamps lie in `start..=end`.
+///
+/// Packet timestamps are deltas, so the new stream starts with a long timestamp packet that restores the time at
+/// which the first kept packet was recorded.
+pub fn trim_sqtt(i: &[u8], desc: RgpSqttDesc, options: SqttOptions, start: u64, end: u64) -> Result<Vec<u8>> {
+    if i.len() <= 8 {
+        // Nothing to decode, see `BitReader::new`.
+        return Ok(i.to_vec());
+    }
+
+    let mut window: Option<(PacketSpan, usize)> = None;
+    parse_sqtt_with(i, desc, options, |span| {
+        if (start..=end).contains(&span.timestamp) {
+            let span_end = span.bit_offset + span.bit_len;
+            match &mut window {
+                Some((_, window_end)) => *window_end = span_end,
+                None => window = Some((span, span_end)),
+            }
+        }
+    })?;
+
+    let mut writer = NibbleWriter::default();
+    // The reader starts decoding after the first 8 bytes, so carry those over unchanged.
+    for &byte in &i[..cmp::min(8, i.len())] {
+        writer.push(byte & 0xf);
+        writer.push(byte >> 4);
+    }
+    // A long timestamp packet of type 1 advances the timestamp by [63:16]. gfx9 decodes the same bits as a timestamp
+    // token setting the absolute time, which is equivalent at the start of the stream.
+    let base_timestamp = window.as_ref().map_or(0, |(first, _)| first.start_timestamp);
+    let long_timestamp = 0x01 | 1 << 14 | (base_timestamp & ((1 << 48) - 1)) << 16;
+    for nibble in 0..16 {
+        writer.push((long_timestamp >> (nibble * 4)) as u8 & 0xf);
+    }
+    if let Some((first, window_end)) = window {
+        for nibble in first.bit_offset / 4..cmp::min(window_end, i.len() * 8) / 4 {
+            writer.push(i[nibble / 2] >> (nibble % 2 * 4) & 0xf);
+        }
+    }
+    Ok(writer.finish())
+}
+
+/// Packs 4-bit values, least significant nibble first.
+#[derive(Default)]
+struct NibbleWriter {
+    output: Vec<u8>,
+    len: usize,
+}
+
+impl NibbleWriter {
+    fn push(&mut self, nibble: u8) {
+        if self.len.is_multiple_of(2) {
+            self.output.push(nibble);
+        } else {
+            *self.output.last_mut().unwrap() |= nibble << 4;
+        }
+        self.len += 1;
+    }
+
+    /// Pads the stream with zero nibbles to a multiple of 4 bytes, like drivers do.
+    fn finish(mut self) -> Vec<u8> {
+        let padded_len = (self.output.len() + 3) & !3;
+        self.output.resize(padded_len, 0);
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use scroll::{Pread, LE};
+
+    use super::*;
+
+    const ENCODINGS: [SqttEncoding; 3] = [SqttEncoding::Gfx9, SqttEncoding::Gfx10, SqttEncoding::Gfx10_3];
+
+    /// xorshift64, to keep the streams reproducible without pulling in a RNG crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    /// Decodes `input` with both readers and checks that they agree.
+    fn check(input: &[u8], options: SqttOptions) {
+        let fast = decode(BitReader::new(input), input.len(), Default::default(), options, |_| {});
+        let checked = decode(
+            CheckedBitReader::new(input),
+            input.len(),
+            Default::default(),
+            options,
+            |_| {},
+        );
+        match (fast, checked) {
+            (Ok(fast), Ok(checked)) => assert_eq!(fast, checked, "{:?} {:x?}", options, input),
+            (Err(_), Err(_)) => {}
+            (fast, checked) => panic!(
+                "readers disagree on {:?} {:x?}: {:?} vs {:?}",
+                options,
+                input,
+                fast.err(),
+                checked.err()
+            ),
+        }
+    }
+
+    #[test]
+    fn random_bytes() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..2000 {
+            let len = (rng.next() % 256) as usize;
+            let input: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            for encoding in ENCODINGS {
+                for resync in [false, true] {
+                    check(&input, SqttOptions { encoding, resync });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_packets() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for _ in 0..500 {
+            for encoding in ENCODINGS {
+                let pkt_len_table = build_packet_length_table(encoding);
+                let mut writer = NibbleWriter::default();
+                for _ in 0..16 {
+                    writer.push(rng.next() as u8 & 0xf);
+                }
+                let packets = rng.next() % 64;
+                let mut written = 0;
+                while written < packets {
+                    let selector = rng.next() as u8;
+                    let Some(pkt_len) = pkt_len_table[selector as usize] else {
+                        continue;
+                    };
+                    // The high nibble of the selector belongs to the next packet for 4 bit packets.
+                    writer.push(selector & 0xf);
+                    if pkt_len.get() > 4 {
+                        writer.push(selector >> 4);
+                        for _ in 2..pkt_len.get() / 4 {
+                            writer.push(rng.next() as u8 & 0xf);
+                        }
+                    }
+                    written += 1;
+                }
+                let input = writer.finish();
+                // Also decode every truncation to exercise the end of the stream.
+                for len in (0..=input.len()).rev().step_by(7) {
+                    check(
+                        &input[..len],
+                        SqttOptions {
+                            encoding,
+                            resync: false,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Appends the low `bits` bits of `value`, which must be a multiple of 4.
+    fn push_bits(writer: &mut NibbleWriter, value: u64, bits: usize) {
+        for nibble in 0..bits / 4 {
+            writer.push((value >> (nibble * 4)) as u8 & 0xf);
+        }
+    }
+
+    /// Appends a ShortTimestamp advancing by `dt_4 + 4`, then an ImmediateOne advancing by `dt`, for each wave.
+    fn push_waves(writer: &mut NibbleWriter, waves: std::ops::Range<u64>) {
+        for i in waves {
+            push_bits(writer, 0x8 | (i % 16) << 4, 8);
+            push_bits(writer, 0xd | (i % 8) << 4 | i << 7, 12);
+        }
+    }
+
+    #[test]
+    fn trim_round_trip() {
+        let options = SqttOptions {
+            encoding: SqttEncoding::Gfx10_3,
+            resync: false,
+        };
+        let mut writer = NibbleWriter::default();
+        push_bits(&mut writer, 0, 64);
+        push_bits(&mut writer, 0x01 | 1 << 14 | 1000 << 16, 64);
+        push_waves(&mut writer, 0..20);
+        let input = writer.finish();
+        let chunk = parse_sqtt(&input, Default::default(), options).unwrap();
+        assert_eq!(chunk.immediate_one.seq.len(), 20);
+
+        let (start, end) = (chunk.immediate_one.timestamp[5], chunk.immediate_one.timestamp[14]);
+        let trimmed = trim_sqtt(&input, Default::default(), options, start, end).unwrap();
+        assert!(trimmed.len() < input.len());
+        let trimmed = parse_sqtt(&trimmed, Default::default(), options).unwrap();
+
+        let kept = |timestamps: &[u64], values: &[u8]| -> Vec<(u64, u8)> {
+            timestamps
+                .iter()
+                .copied()
+                .zip(values.iter().copied())
+                .filter(|(timestamp, _)| (start..=end).contains(timestamp))
+                .collect()
+        };
+        let expected = kept(&chunk.immediate_one.timestamp, &chunk.immediate_one.wave_id);
+        assert_eq!(expected.len(), 10);
+        assert_eq!(
+            kept(&trimmed.immediate_one.timestamp, &trimmed.immediate_one.wave_id),
+            expected
+        );
+        assert_eq!(trimmed.immediate_one.seq.len(), expected.len());
+        // Short timestamps record the time before they advance it, but are kept by the time after.
+        let short_timestamps = |chunk: &SqttChunk| -> Vec<(u64, u8)> {
+            let packets = &chunk.short_timestamp;
+            packets
+                .timestamp
+                .iter()
+                .copied()
+                .zip(packets.dt_4.iter().copied())
+                .filter(|&(timestamp, dt_4)| (start..=end).contains(&(timestamp + dt_4 as u64 + 4)))
+                .collect()
+        };
+        assert_eq!(short_timestamps(&trimmed), short_timestamps(&chunk));
+        assert_eq!(trimmed.short_timestamp.seq.len(), short_timestamps(&chunk).len());
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        const GARBAGE_NIBBLES: usize = 9;
+        let options = SqttOptions {
+            encoding: SqttEncoding::Gfx10_3,
+            resync: true,
+        };
+        let stream = |garbage: bool| {
+            let mut writer = NibbleWriter::default();
+            push_bits(&mut writer, 0, 64);
+            push_bits(&mut writer, 0x01 | 1 << 14 | 1000 << 16, 64);
+            push_waves(&mut writer, 0..5);
+            if garbage {
+                // Packet type 7 is unknown, and no run of it looks like a long timestamp.
+                for _ in 0..GARBAGE_NIBBLES {
+                    writer.push(0x7);
+                }
+            }
+            push_bits(&mut writer, 0x01 | 1 << 14 | 5000 << 16, 64);
+            push_waves(&mut writer, 5..25);
+            writer.finish()
+        };
+
+        let clean = stream(false);
+        let mut spans = vec![];
+        let expected = parse_sqtt_with(&clean, Default::default(), options, |span| spans.push(span)).unwrap();
+        // The garbage is inserted right before the second long timestamp.
+        let resync_span = &spans[11];
+        assert_eq!(resync_span.timestamp - resync_span.start_timestamp, 5000);
+
+        let corrupt = stream(true);
+        assert!(parse_sqtt(
+            &corrupt,
+            Default::default(),
+            SqttOptions {
+                resync: false,
+                ..options
+            }
+        )
+        .is_err());
+        let mut chunk = parse_sqtt(&corrupt, Default::default(), options).unwrap();
+        assert_eq!(
+            chunk.gaps,
+            [DecodeGap {
+                bit_offset: resync_span.bit_offset,
+                bit_len: GARBAGE_NIBBLES * 4,
+                timestamp: resync_span.start_timestamp,
+            }]
+        );
+        // Everything after the gap decodes as if the garbage wasn't there.
+        chunk.gaps.clear();
+        assert_eq!(chunk, expected);
+        assert_eq!(chunk.immediate_one.wave_id, (0..25).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn gfx9_tokens() {
+        let options = SqttOptions {
+            encoding: SqttEncoding::Gfx9,
+            resync: false,
+        };
+        let mut writer = NibbleWriter::default();
+        push_bits(&mut writer, 0, 64);
+        // Timestamp: absolute time 100.
+        push_bits(&mut writer, 0x1 | 100 << 16, 64);
+        // WaveStart: dt 2, simd 1, cu 5, wave 7, stage 3, 63 threads.
+        push_bits(
+            &mut writer,
+            0x3 | 2 << 4 | 1 << 6 | 5 << 8 | 7 << 12 | 3 << 17 | 63 << 25,
+            32,
+        );
+        // Reg: dt 1, write of 0xdeadbeef to register 0x1234, with the bits of the unmapped columns set.
+        push_bits(
+            &mut writer,
+            0x2 | 1 << 4 | 0x7 << 6 | 1 << 15 | 0x1234 << 16 | 0xdeadbeef << 32,
+            64,
+        );
+        // Misc: advances the clock by 10.
+        push_bits(&mut writer, 10 << 4, 16);
+        // WaveEnd: dt 3, simd 2, cu 4, wave 9.
+        push_bits(&mut writer, 0x6 | 3 << 4 | 2 << 6 | 4 << 8 | 9 << 12, 16);
+        // InstPc: wave 6 at pc 0x1000.
+        push_bits(&mut writer, 0xb | 6 << 8 | 0x1000 << 16, 64);
+        let chunk = parse_sqtt(&writer.finish(), Default::default(), options).unwrap();
+
+        let start = &chunk.wave_start;
+        assert_eq!(start.timestamp, [102]);
+        assert_eq!((start.sh[0], start.simd[0], start.wgp[0], start.wave[0]), (0, 1, 5, 7));
+        assert_eq!((start.stage[0], start.threads[0]), (3, 63));
+
+        let reg_write = &chunk.reg_write;
+        assert_eq!(reg_write.timestamp, [103]);
+        assert_eq!(
+            (reg_write.is_write[0], reg_write.reg[0], reg_write.val[0]),
+            (1, 0x1234, 0xdeadbeef)
+        );
+        assert_eq!((reg_write.a0[0], reg_write.a1[0], reg_write.b0[0]), (0, 0, 0));
+
+        let end = &chunk.wave_end;
+        assert_eq!(end.timestamp, [116]);
+        assert_eq!((end.sh[0], end.simd[0], end.wgp[0], end.wave[0]), (0, 2, 4, 9));
+
+        let set_pc = &chunk.set_pc;
+        assert_eq!(set_pc.timestamp, [116]);
+        assert_eq!((set_pc.wave[0], set_pc.pc[0]), (6, 0x1000));
+    }
+
+    #[test]
+    fn encoding_for_gfxip() {
+        let mut asic_info: RgpAsicInfo = [0; 1024].pread_with(0, LE).unwrap();
+        let mut encoding = |gfxip_level: u32, best_effort| {
+            asic_info.gfxip_level = gfxip_level;
+            SqttEncoding::new(&asic_info, best_effort)
+        };
+        assert!(matches!(
+            encoding(SqttGfxipLevel::GfxIp10_3.into(), false),
+            Ok(SqttEncoding::Gfx10_3)
+        ));
+        assert!(matches!(
+            encoding(SqttGfxipLevel::GfxIp11.into(), false),
+            Err(SqttError::UnsupportedGfxip(SqttGfxipLevel::GfxIp11))
+        ));
+        assert!(matches!(
+            encoding(SqttGfxipLevel::GfxIp11.into(), true),
+            Ok(SqttEncoding::Gfx10)
+        ));
+        assert!(matches!(encoding(0x42, false), Err(SqttError::UnknownGfxip(0x42))));
+        assert!(matches!(encoding(0x42, true), Ok(SqttEncoding::Gfx10)));
+    }
+
+    #[test]
+    fn empty_input() {
+        for len in 0..=8 {
+            let input = vec![0xff; len];
+            for encoding in ENCODINGS {
+                let options = SqttOptions {
+                    encoding,
+                    resync: false,
+                };
+                let chunk = decode(BitReader::new(&input), len, Default::default(), options, |_| {}).unwrap();
+                assert_eq!(chunk, SqttChunk::default());
+            }
+        }
+    }
+}
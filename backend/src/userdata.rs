@@ -1,7 +1,29 @@
 use anyhow::{bail, Context, Result};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use tracing::warn;
 
-#[derive(TryFromPrimitive, IntoPrimitive, Debug)]
+use crate::merge::{MergedIterator, MergedIteratorItem};
+use crate::sqtt::{SqttChunk, SqttInitiatorType};
+
+const SQ_THREAD_TRACE_USERDATA_2: u32 = 0x030D08 / 4;
+const SQ_THREAD_TRACE_USERDATA_3: u32 = 0x030D0C / 4;
+const THREAD_TRACE_MARKER: u32 = 53;
+
+/// The instrumentation spec and API versions whose marker layouts are implemented here.
+pub const INSTRUMENTATION_SPEC_VERSION: u16 = 1;
+pub const INSTRUMENTATION_API_VERSION: u16 = 0;
+
+/// Warns if a capture uses marker layouts we don't understand.
+pub fn check_instrumentation_version(spec_version: u16, api_version: u16) {
+    if spec_version != INSTRUMENTATION_SPEC_VERSION || api_version != INSTRUMENTATION_API_VERSION {
+        warn!(
+            "capture uses instrumentation spec {} API {}, but only spec {} API {} is supported; markers may be decoded incorrectly",
+            spec_version, api_version, INSTRUMENTATION_SPEC_VERSION, INSTRUMENTATION_API_VERSION
+        );
+    }
+}
+
+#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u8)]
 // For several marker types we don't know their layouts; those are omitted from the enum.
 pub enum RgpSqttMarkerIdentifier {
@@ -22,7 +44,7 @@ pub struct SqttUserdata {
 
 impl SqttUserdata {
     pub fn new(dw: Vec<u32>) -> Result<SqttUserdata> {
-        if dw.len() == 0 {
+        if dw.is_empty() {
             bail!("Userdata is empty");
         }
         let ret = SqttUserdata { dw };
@@ -54,6 +76,85 @@ impl SqttUserdata {
     }
 
     pub fn api_type(&self) -> u32 {
-        ((self.dw[0] >> 7) & ((1 << 20) - 1)) as u32
+        (self.dw[0] >> 7) & ((1 << 20) - 1)
+    }
+
+    /// The command buffer id of a CbStart or CbEnd marker.
+    pub fn cb_id(&self) -> Option<u32> {
+        match self.id() {
+            RgpSqttMarkerIdentifier::CbStart | RgpSqttMarkerIdentifier::CbEnd => {
+                Some((self.dw[0] >> 7) & ((1 << 20) - 1))
+            }
+            _ => None,
+        }
+    }
+
+    /// The API PSO hash of a BindPipeline marker.
+    pub fn api_pso_hash(&self) -> Option<u64> {
+        match self.id() {
+            RgpSqttMarkerIdentifier::BindPipeline => Some(self.dw[1] as u64 | (self.dw[2] as u64) << 32),
+            _ => None,
+        }
+    }
+}
+
+/// A marker reassembled from userdata register writes.
+pub struct SqttMarker {
+    /// The SQTT timestamp of the first dword.
+    pub first_timestamp: u64,
+    /// The SQTT timestamp of the last dword.
+    pub timestamp: u64,
+    pub userdata: SqttUserdata,
+}
+
+/// Reassembles the markers written by the driver from the userdata register writes in a chunk.
+pub fn decode_markers(chunk: &SqttChunk) -> Vec<SqttMarker> {
+    let reg_write = &chunk.reg_write;
+    let initiator = &chunk.initiator;
+    let iter = MergedIterator::new(vec![&reg_write.seq, &initiator.seq]);
+
+    let mut userdata_buf = vec![];
+    let mut first_timestamp = 0;
+    let mut markers = vec![];
+    for MergedIteratorItem { kind, index: i } in iter {
+        match kind {
+            0 => {
+                if reg_write.reg[i] as u32 == SQ_THREAD_TRACE_USERDATA_2
+                    || reg_write.reg[i] as u32 == SQ_THREAD_TRACE_USERDATA_3
+                {
+                    if userdata_buf.is_empty() {
+                        first_timestamp = reg_write.timestamp[i];
+                    }
+                    userdata_buf.push(reg_write.val[i]);
+                    match SqttUserdata::len(userdata_buf[0]) {
+                        Ok(len) if len == userdata_buf.len() => {
+                            markers.push(SqttMarker {
+                                first_timestamp,
+                                timestamp: reg_write.timestamp[i],
+                                userdata: SqttUserdata::new(userdata_buf).unwrap(),
+                            });
+                            userdata_buf = vec![];
+                        }
+                        Ok(_) => {}
+                        // E.g. the tail of a marker cut off at the start of a trimmed capture.
+                        Err(_) => {
+                            warn!("skipping userdata with unknown marker type");
+                            userdata_buf.clear();
+                        }
+                    }
+                }
+            }
+            1 => {
+                if initiator.kind(i) == Some(SqttInitiatorType::Event)
+                    && initiator.id(i) == THREAD_TRACE_MARKER
+                    && !userdata_buf.is_empty()
+                {
+                    warn!("encountered initiator but userdata packet is incomplete");
+                    userdata_buf.clear(); // Try to re-synchronize
+                }
+            }
+            _ => unreachable!(),
+        }
     }
+    markers
 }
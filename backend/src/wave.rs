@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use crate::merge::{MergedIterator, MergedIteratorItem};
+use crate::sqtt::SqttChunk;
+
+/// The hardware slot a wave occupies. Slots are reused once a wave ends.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct WaveSlot {
+    pub sh: u8,
+    pub simd: u8,
+    pub wgp: u8,
+    pub wave: u8,
+}
+
+/// The lifetime of a wave, in SQTT timestamps. Events that happened outside the trace are `None`.
+#[derive(Clone, Debug)]
+pub struct Wave {
+    pub slot: WaveSlot,
+    pub alloc: Option<u64>,
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    pub stage: u8,
+    pub threads: u8,
+}
+
+impl Wave {
+    fn new(slot: WaveSlot) -> Self {
+        Wave {
+            slot,
+            alloc: None,
+            start: None,
+            end: None,
+            stage: 0,
+            threads: 0,
+        }
+    }
+
+    fn first_timestamp(&self) -> u64 {
+        self.alloc.or(self.start).or(self.end).unwrap_or(0)
+    }
+}
+
+/// Matches the WaveAlloc, WaveStart and WaveEnd packets of a chunk by slot into waves, sorted by their first event and
+/// then by slot.
+///
+/// A wave whose packets are out of order (e.g. a second WaveStart before the WaveEnd, because packets were lost) is
+/// closed early and a new one is started, rather than merging two waves into one.
+pub fn reconstruct_waves(chunk: &SqttChunk) -> Vec<Wave> {
+    let (alloc, start, end) = (&chunk.wave_alloc, &chunk.wave_start, &chunk.wave_end);
+    let iter = MergedIterator::new(vec![&alloc.seq, &start.seq, &end.seq]);
+
+    let mut open: HashMap<WaveSlot, Wave> = HashMap::new();
+    let mut waves = vec![];
+    for MergedIteratorItem { kind, index: i } in iter {
+        match kind {
+            0 => {
+                let slot = WaveSlot {
+                    sh: alloc.sh[i],
+                    simd: alloc.simd[i],
+                    wgp: alloc.wgp[i],
+                    wave: alloc.wave[i],
+                };
+                let mut wave = Wave::new(slot);
+                wave.alloc = Some(alloc.timestamp[i]);
+                waves.extend(open.insert(slot, wave));
+            }
+            1 => {
+                let slot = WaveSlot {
+                    sh: start.sh[i],
+                    simd: start.simd[i],
+                    wgp: start.wgp[i],
+                    wave: start.wave[i],
+                };
+                let mut wave = match open.remove(&slot) {
+                    Some(wave) if wave.start.is_none() => wave,
+                    previous => {
+                        waves.extend(previous);
+                        Wave::new(slot)
+                    }
+                };
+                wave.start = Some(start.timestamp[i]);
+                wave.stage = start.stage[i];
+                wave.threads = start.threads[i];
+                open.insert(slot, wave);
+            }
+            2 => {
+                let slot = WaveSlot {
+                    sh: end.sh[i],
+                    simd: end.simd[i],
+                    wgp: end.wgp[i],
+                    wave: end.wave[i],
+                };
+                let mut wave = open.remove(&slot).unwrap_or_else(|| Wave::new(slot));
+                wave.end = Some(end.timestamp[i]);
+                waves.push(wave);
+            }
+            _ => unreachable!(),
+        }
+    }
+    waves.extend(open.into_values());
+    // Waves still open come out of the map in arbitrary order, so break ties to keep the result deterministic.
+    waves.sort_by_key(|wave| (wave.first_timestamp(), wave.slot));
+    waves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOC: usize = 0;
+    const START: usize = 1;
+    const END: usize = 2;
+
+    const SLOT: WaveSlot = WaveSlot {
+        sh: 0,
+        simd: 1,
+        wgp: 2,
+        wave: 3,
+    };
+    const OTHER_SLOT: WaveSlot = WaveSlot {
+        sh: 1,
+        simd: 0,
+        wgp: 0,
+        wave: 0,
+    };
+
+    /// Builds a chunk from `(kind, slot, timestamp)` packets in stream order.
+    fn chunk(packets: &[(usize, WaveSlot, u64)]) -> SqttChunk {
+        macro_rules! push {
+            ($column:expr, $seq:expr, $slot:expr, $timestamp:expr) => {{
+                let column = &mut $column;
+                column.seq.push($seq);
+                column.timestamp.push($timestamp);
+                column.sh.push($slot.sh);
+                column.simd.push($slot.simd);
+                column.wgp.push($slot.wgp);
+                column.wave.push($slot.wave);
+            }};
+        }
+
+        let mut chunk = SqttChunk::default();
+        for (seq, &(kind, slot, timestamp)) in packets.iter().enumerate() {
+            let seq = seq as u32;
+            match kind {
+                ALLOC => push!(chunk.wave_alloc, seq, slot, timestamp),
+                START => {
+                    push!(chunk.wave_start, seq, slot, timestamp);
+                    chunk.wave_start.stage.push(1);
+                    chunk.wave_start.threads.push(64);
+                }
+                END => push!(chunk.wave_end, seq, slot, timestamp),
+                _ => unreachable!(),
+            }
+        }
+        chunk
+    }
+
+    /// Slot, alloc, start and end of a wave.
+    type Lifetime = (WaveSlot, Option<u64>, Option<u64>, Option<u64>);
+
+    fn lifetimes(waves: &[Wave]) -> Vec<Lifetime> {
+        waves
+            .iter()
+            .map(|wave| (wave.slot, wave.alloc, wave.start, wave.end))
+            .collect()
+    }
+
+    #[test]
+    fn complete_wave() {
+        let waves = reconstruct_waves(&chunk(&[(ALLOC, SLOT, 10), (START, SLOT, 20), (END, SLOT, 30)]));
+        assert_eq!(lifetimes(&waves), [(SLOT, Some(10), Some(20), Some(30))]);
+        assert_eq!((waves[0].stage, waves[0].threads), (1, 64));
+    }
+
+    #[test]
+    fn slot_reuse() {
+        let waves = reconstruct_waves(&chunk(&[
+            (ALLOC, SLOT, 10),
+            (START, SLOT, 20),
+            (END, SLOT, 30),
+            (ALLOC, SLOT, 40),
+            (START, SLOT, 50),
+            (END, SLOT, 60),
+        ]));
+        assert_eq!(
+            lifetimes(&waves),
+            [
+                (SLOT, Some(10), Some(20), Some(30)),
+                (SLOT, Some(40), Some(50), Some(60))
+            ]
+        );
+    }
+
+    #[test]
+    fn outside_the_trace() {
+        // One wave was allocated before the trace started, the other one ends after it stopped.
+        let waves = reconstruct_waves(&chunk(&[
+            (START, SLOT, 20),
+            (ALLOC, OTHER_SLOT, 25),
+            (END, SLOT, 30),
+            (START, OTHER_SLOT, 35),
+        ]));
+        assert_eq!(
+            lifetimes(&waves),
+            [(SLOT, None, Some(20), Some(30)), (OTHER_SLOT, Some(25), Some(35), None)]
+        );
+    }
+
+    #[test]
+    fn second_start() {
+        // The WaveEnd of the first wave and the WaveAlloc of the second one were lost.
+        let waves = reconstruct_waves(&chunk(&[
+            (ALLOC, SLOT, 10),
+            (START, SLOT, 20),
+            (START, SLOT, 30),
+            (END, SLOT, 40),
+        ]));
+        assert_eq!(
+            lifetimes(&waves),
+            [(SLOT, Some(10), Some(20), None), (SLOT, None, Some(30), Some(40))]
+        );
+    }
+
+    #[test]
+    fn ties_sorted_by_slot() {
+        let waves = reconstruct_waves(&chunk(&[(ALLOC, OTHER_SLOT, 10), (ALLOC, SLOT, 10)]));
+        assert_eq!(
+            lifetimes(&waves),
+            [(SLOT, Some(10), None, None), (OTHER_SLOT, Some(10), None, None)]
+        );
+    }
+}
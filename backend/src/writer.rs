@@ -0,0 +1,535 @@
+use anyhow::Result;
+use scroll::ctx::SizeWith;
+use scroll::{Pread, Pwrite, LE};
+use tracing::warn;
+
+use crate::rgp::{
+    parse_pso_correlation, parse_queue_event_timings, parse_spm_db, push_struct, validate, write_pso_correlation,
+    ChunkId, RgpApiInfo, RgpCpuInfo, RgpEntryHeader, RgpError, RgpHeader, RgpQueueEventRecord, RgpQueueEventTimings,
+    RgpSpmCounterInfo, RgpSpmDb, RgpSqttData, RgpSqttDesc, SqttFileChunkType, SqttProfilingMode, ENTRY_HEADER_SIZE,
+    QUEUE_EVENT_TIMINGS_HEADER_SIZE, SQTT_DATA_HEADER_SIZE,
+};
+use crate::sqtt::{trim_sqtt, SqttOptions};
+
+/// Builds an RGP file chunk by chunk.
+pub struct RgpWriter {
+    buf: Vec<u8>,
+}
+
+impl RgpWriter {
+    /// Starts a file with the given header. The chunk offset is filled in.
+    pub fn new(header: &RgpHeader) -> Result<Self, scroll::Error> {
+        let mut buf = vec![];
+        let header = RgpHeader {
+            chunk_offset: RgpHeader::size_with(&LE) as u32,
+            ..header.clone()
+        };
+        push_struct(&mut buf, header)?;
+        Ok(RgpWriter { buf })
+    }
+
+    /// Appends a chunk with `payload` as everything after the chunk header. The chunk size is taken from `payload`
+    /// instead of `header`, and file offsets stored in the payload are updated to the new location of the chunk.
+    pub fn add_chunk(&mut self, header: &RgpEntryHeader, payload: &[u8]) -> Result<(), scroll::Error> {
+        use SqttFileChunkType::*;
+
+        let offset = self.buf.len();
+        let header = RgpEntryHeader {
+            chunk_id: ChunkId {
+                reserved: 0,
+                ..header.chunk_id.clone()
+            },
+            size: (ENTRY_HEADER_SIZE + payload.len()) as u32,
+            reserved: 0,
+            ..header.clone()
+        };
+        push_struct(&mut self.buf, header.clone())?;
+        let payload_offset = self.buf.len();
+        self.buf.extend_from_slice(payload);
+
+        // Chunks of a version we don't know are copied verbatim: we can't tell where their offsets are.
+        match header.known_type() {
+            Some(SqttData) => {
+                let data_offset = (payload_offset + SQTT_DATA_HEADER_SIZE) as u32;
+                self.buf.pwrite_with(data_offset, payload_offset, LE)?;
+            }
+            Some(CodeObjectDatabase | CodeObjectLoaderEvents | PsoCorrelation) => {
+                self.buf.pwrite_with(offset as u32, payload_offset, LE)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Rewrites a capture without strings identifying the host or application: the CPU vendor and brand, pipeline debug
+/// names, and the user markers bounding the trace. The labels of user events in the SQTT data are kept, as we can't
+/// tell how long they are.
+pub fn anonymize(data: &[u8]) -> Result<Vec<u8>> {
+    let (header, chunks) = validate(data)?;
+    let mut writer = RgpWriter::new(&header)?;
+    for (offset, entry) in chunks {
+        let payload = &data[offset + ENTRY_HEADER_SIZE..offset + entry.size as usize];
+        let scrubbed = [
+            SqttFileChunkType::CpuInfo as u8,
+            SqttFileChunkType::PsoCorrelation as u8,
+            SqttFileChunkType::ApiInfo as u8,
+        ];
+        match entry.known_type() {
+            None if scrubbed.contains(&entry.chunk_id.ty) => {
+                warn!(
+                    "dropping chunk of type {} version {}.{} at offset {}: its layout is unknown",
+                    entry.chunk_id.ty, entry.version_major, entry.version_minor, offset
+                );
+            }
+            Some(SqttFileChunkType::CpuInfo) => {
+                let mut info: RgpCpuInfo = payload.pread_with(0, LE)?;
+                info.vendor_id = [0; 16];
+                info.processor_brand = [0; 48];
+                let mut payload = payload.to_vec();
+                payload.pwrite_with(info, 0, LE)?;
+                writer.add_chunk(&entry, &payload)?;
+            }
+            Some(SqttFileChunkType::ApiInfo) => {
+                let mut info: RgpApiInfo = payload.pread_with(0, LE)?;
+                if info.profiling_mode == SqttProfilingMode::UserMarkers.into() {
+                    info.profiling_mode_data = [0; 512];
+                }
+                let mut payload = payload.to_vec();
+                payload.pwrite_with(info, 0, LE)?;
+                writer.add_chunk(&entry, &payload)?;
+            }
+            Some(SqttFileChunkType::PsoCorrelation) => {
+                let mut records = parse_pso_correlation(payload)?;
+                for record in &mut records {
+                    record.api_level_obj_name = [0; 64];
+                }
+                writer.add_chunk(&entry, &write_pso_correlation(&records)?)?;
+            }
+            _ => writer.add_chunk(&entry, payload)?,
+        }
+    }
+    Ok(writer.finish())
+}
+
+/// Rewrites a capture keeping only the SQTT packets with timestamps in `start..=end`, and the SPM samples and queue
+/// events within `gpu_window`, the same window in GPU timestamps. Without a `gpu_window` those are kept in full, as are
+/// the other chunks.
+pub fn trim(
+    data: &[u8],
+    options: SqttOptions,
+    start: u64,
+    end: u64,
+    gpu_window: Option<(u64, u64)>,
+) -> Result<Vec<u8>> {
+    let (header, chunks) = validate(data)?;
+    let payload =
+        |offset: usize, entry: &RgpEntryHeader| &data[offset + ENTRY_HEADER_SIZE..offset + entry.size as usize];
+    let sqtt_desc = |index: u8| -> Result<RgpSqttDesc> {
+        let (offset, entry) = chunks
+            .iter()
+            .find(|(_, entry)| entry.chunk_id.ty == SqttFileChunkType::SqttDesc as u8 && entry.chunk_id.index == index)
+            .ok_or(RgpError::MissingSqttDesc(index))?;
+        Ok(payload(*offset, entry).pread_with(0, LE)?)
+    };
+
+    let mut writer = RgpWriter::new(&header)?;
+    for (offset, entry) in &chunks {
+        let payload = payload(*offset, entry);
+        match (entry.known_type(), gpu_window) {
+            (Some(SqttFileChunkType::SqttData), _) => {}
+            (Some(SqttFileChunkType::SpmDb), Some((gpu_start, gpu_end))) => {
+                writer.add_chunk(entry, &trim_spm_db(payload, gpu_start, gpu_end)?)?;
+                continue;
+            }
+            (Some(SqttFileChunkType::QueueEventTimings), Some((gpu_start, gpu_end))) => {
+                writer.add_chunk(entry, &trim_queue_event_timings(payload, gpu_start, gpu_end)?)?;
+                continue;
+            }
+            _ => {
+                writer.add_chunk(entry, payload)?;
+                continue;
+            }
+        }
+        let sqtt_header: RgpSqttData = payload.pread_with(0, LE)?;
+        let sqtt_data: &[u8] = payload.pread_with(SQTT_DATA_HEADER_SIZE, sqtt_header.size as usize)?;
+        let sqtt_data = trim_sqtt(sqtt_data, sqtt_desc(entry.chunk_id.index)?, options, start, end)?;
+
+        let mut payload = vec![];
+        let sqtt_header = RgpSqttData {
+            offset: 0,
+            size: sqtt_data.len() as u32,
+        };
+        push_struct(&mut payload, sqtt_header)?;
+        payload.extend_from_slice(&sqtt_data);
+        writer.add_chunk(entry, &payload)?;
+    }
+    Ok(writer.finish())
+}
+
+/// Keeps the SPM samples taken in `start..=end`. The preamble and the counter info records are kept as they are, apart
+/// from the sample count and offsets.
+fn trim_spm_db(chunk: &[u8], start: u64, end: u64) -> Result<Vec<u8>> {
+    let trace = parse_spm_db(chunk)?;
+    let kept: Vec<usize> = (0..trace.timestamps.len())
+        .filter(|&i| (start..=end).contains(&trace.timestamps[i]))
+        .collect();
+
+    let preamble_size = (trace.header.preamble_size as usize).saturating_sub(ENTRY_HEADER_SIZE);
+    let mut out = chunk[..preamble_size].to_vec();
+    let header = RgpSpmDb {
+        num_timestamps: kept.len() as u32,
+        ..trace.header.clone()
+    };
+    out.pwrite_with(header, 0, LE)?;
+    for &i in &kept {
+        push_struct(&mut out, trace.timestamps[i])?;
+    }
+
+    let info_size = trace.header.spm_counter_info_size as usize;
+    let infos_offset = out.len();
+    let old_infos_offset = preamble_size + trace.timestamps.len() * 8;
+    out.extend_from_slice(&chunk[old_infos_offset..old_infos_offset + trace.counters.len() * info_size]);
+    for (index, counter) in trace.counters.into_iter().enumerate() {
+        let info = RgpSpmCounterInfo {
+            data_offset: (ENTRY_HEADER_SIZE + out.len()) as u32,
+            ..counter.info
+        };
+        out.pwrite_with(info, infos_offset + index * info_size, LE)?;
+        for &i in &kept {
+            push_struct(&mut out, counter.values[i])?;
+        }
+    }
+    Ok(out)
+}
+
+/// Keeps the queue events whose GPU timestamps overlap `start..=end`, and those without GPU timestamps. The queue
+/// info table is kept as it is.
+fn trim_queue_event_timings(chunk: &[u8], start: u64, end: u64) -> Result<Vec<u8>> {
+    let header: RgpQueueEventTimings = chunk.pread_with(0, LE)?;
+    let (_, events) = parse_queue_event_timings(chunk)?;
+    let events: Vec<_> = events
+        .into_iter()
+        .filter(|event| {
+            let [event_start, event_end] = event.gpu_timestamps;
+            event_start == 0 || (event_start <= end && event_start.max(event_end) >= start)
+        })
+        .collect();
+
+    let queue_info_end = QUEUE_EVENT_TIMINGS_HEADER_SIZE + header.queue_info_table_size as usize;
+    let mut out = chunk[..queue_info_end].to_vec();
+    let header = RgpQueueEventTimings {
+        queue_event_table_record_count: events.len() as u32,
+        queue_event_table_size: (events.len() * RgpQueueEventRecord::size_with(&LE)) as u32,
+        ..header
+    };
+    out.pwrite_with(header, 0, LE)?;
+    for event in events {
+        push_struct(&mut out, event)?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use scroll::ctx::TryFromCtx;
+    use scroll::Endian;
+
+    use super::*;
+    use crate::rgp::{
+        RgpAsicInfo, RgpCodeObjectDatabase, RgpCodeObjectLoaderEventRecord, RgpCodeObjectLoaderEvents,
+        RgpPsoCorrelationRecord, RgpQueueInfoRecord, SqttGfxipLevel, SqttLoaderEventType, SqttQueueEventType,
+        RGP_MAGIC_NUMBER, RGP_VERSION_MAJOR,
+    };
+
+    fn zeroed<T>() -> T
+    where
+        T: SizeWith<Endian> + for<'a> TryFromCtx<'a, Endian, Error = scroll::Error>,
+    {
+        vec![0; T::size_with(&LE)].pread_with(0, LE).unwrap()
+    }
+
+    fn entry(ty: SqttFileChunkType) -> RgpEntryHeader {
+        RgpEntryHeader {
+            chunk_id: ChunkId {
+                ty: ty as u8,
+                index: 0,
+                reserved: 0,
+            },
+            version_minor: 0,
+            version_major: 0,
+            size: 0,
+            reserved: 0,
+        }
+    }
+
+    const STALE_OFFSET: u32 = 0xdeadbeef;
+
+    #[test]
+    fn offsets_are_rewritten() {
+        use SqttFileChunkType::*;
+
+        let hash = [1, 2];
+        let mut code_object = b"\x7fELF".to_vec();
+        code_object.resize(64, 0);
+        code_object.push(0xa0 | 23);
+        code_object.extend_from_slice(b".internal_pipeline_hash");
+        code_object.extend_from_slice(&[0x92, 0x01, 0x02]);
+
+        let mut header: RgpHeader = zeroed();
+        header.magic_number = RGP_MAGIC_NUMBER;
+        header.version_major = RGP_VERSION_MAJOR;
+        let mut writer = RgpWriter::new(&header).unwrap();
+
+        let mut asic_info: RgpAsicInfo = zeroed();
+        asic_info.gfxip_level = SqttGfxipLevel::GfxIp10_3.into();
+        asic_info.trace_shader_core_clock = 1_000_000_000;
+        let mut payload = vec![];
+        push_struct(&mut payload, asic_info).unwrap();
+        writer.add_chunk(&entry(AsicInfo), &payload).unwrap();
+
+        let mut payload = vec![];
+        push_struct(&mut payload, RgpSqttDesc::default()).unwrap();
+        writer.add_chunk(&entry(SqttDesc), &payload).unwrap();
+
+        let mut payload = vec![];
+        let sqtt_data = RgpSqttData {
+            offset: STALE_OFFSET,
+            size: 16,
+        };
+        push_struct(&mut payload, sqtt_data).unwrap();
+        payload.extend_from_slice(&[0; 16]);
+        writer.add_chunk(&entry(SqttData), &payload).unwrap();
+
+        let mut payload = vec![];
+        let database = RgpCodeObjectDatabase {
+            offset: STALE_OFFSET,
+            flags: 0,
+            size: (16 + 4 + code_object.len()) as u32,
+            record_count: 1,
+        };
+        push_struct(&mut payload, database).unwrap();
+        push_struct(&mut payload, code_object.len() as u32).unwrap();
+        payload.extend_from_slice(&code_object);
+        writer.add_chunk(&entry(CodeObjectDatabase), &payload).unwrap();
+
+        let mut payload = vec![];
+        let loader_events = RgpCodeObjectLoaderEvents {
+            offset: STALE_OFFSET,
+            flags: 0,
+            record_size: RgpCodeObjectLoaderEventRecord::size_with(&LE) as u32,
+            record_count: 1,
+        };
+        push_struct(&mut payload, loader_events).unwrap();
+        let load = RgpCodeObjectLoaderEventRecord {
+            loader_event_type: SqttLoaderEventType::CodeObjectLoadToGpuMemory.into(),
+            reserved: 0,
+            base_address: 0x1000,
+            code_object_hash: hash,
+            time_stamp: 0,
+        };
+        push_struct(&mut payload, load).unwrap();
+        writer.add_chunk(&entry(CodeObjectLoaderEvents), &payload).unwrap();
+
+        let mut payload = write_pso_correlation(&[RgpPsoCorrelationRecord {
+            api_pso_hash: 3,
+            pipeline_hash: hash,
+            api_level_obj_name: [0; 64],
+        }])
+        .unwrap();
+        payload.pwrite_with(STALE_OFFSET, 0, LE).unwrap();
+        writer.add_chunk(&entry(PsoCorrelation), &payload).unwrap();
+
+        let data = writer.finish();
+        let (_, chunks) = validate(&data).unwrap();
+        assert_eq!(chunks.len(), 6);
+        for (offset, entry) in &chunks {
+            let stored: u32 = data.pread_with(offset + ENTRY_HEADER_SIZE, LE).unwrap();
+            match SqttFileChunkType::try_from(entry.chunk_id.ty).unwrap() {
+                SqttData => assert_eq!(stored as usize, offset + ENTRY_HEADER_SIZE + SQTT_DATA_HEADER_SIZE),
+                CodeObjectDatabase | CodeObjectLoaderEvents | PsoCorrelation => assert_eq!(stored as usize, *offset),
+                _ => {}
+            }
+        }
+
+        let service = crate::parse(&data, false, false).unwrap();
+        assert_eq!(service.chunks.len(), 1);
+        assert_eq!(service.code_objects.len(), 1);
+        assert_eq!(service.code_objects[0].hash, Some(hash));
+        assert_eq!(service.code_objects[0].data, code_object);
+        let residencies = service.code_object_resolver.residencies();
+        assert_eq!(residencies.len(), 1);
+        assert_eq!(residencies[0].size, Some(code_object.len() as u64));
+        assert_eq!(service.pso_correlations[&3].pipeline_hash, hash);
+    }
+
+    /// A capture with the chunks needed to load it, the AsicInfo at `asic_info_major`, and a CpuInfo chunk from a newer
+    /// version of the format.
+    fn capture_with_newer_cpu_info(asic_info_major: u16) -> Vec<u8> {
+        use SqttFileChunkType::*;
+
+        let mut header: RgpHeader = zeroed();
+        header.magic_number = RGP_MAGIC_NUMBER;
+        header.version_major = RGP_VERSION_MAJOR;
+        let mut writer = RgpWriter::new(&header).unwrap();
+
+        let mut asic_info: RgpAsicInfo = zeroed();
+        asic_info.gfxip_level = SqttGfxipLevel::GfxIp10_3.into();
+        asic_info.trace_shader_core_clock = 1_000_000_000;
+        let mut payload = vec![];
+        push_struct(&mut payload, asic_info).unwrap();
+        let asic_info_entry = RgpEntryHeader {
+            version_major: asic_info_major,
+            ..entry(AsicInfo)
+        };
+        writer.add_chunk(&asic_info_entry, &payload).unwrap();
+
+        let mut payload = vec![];
+        push_struct(&mut payload, RgpSqttDesc::default()).unwrap();
+        writer.add_chunk(&entry(SqttDesc), &payload).unwrap();
+
+        let mut payload = vec![];
+        push_struct(&mut payload, RgpSqttData { offset: 0, size: 16 }).unwrap();
+        payload.extend_from_slice(&[0; 16]);
+        writer.add_chunk(&entry(SqttData), &payload).unwrap();
+
+        let cpu_info_entry = RgpEntryHeader {
+            version_major: 1,
+            ..entry(CpuInfo)
+        };
+        writer.add_chunk(&cpu_info_entry, b"GenuineIntel").unwrap();
+        writer.finish()
+    }
+
+    #[test]
+    fn newer_chunk_versions() {
+        let data = capture_with_newer_cpu_info(0);
+        let service = crate::parse(&data, false, false).unwrap();
+        assert_eq!(service.unknown_chunks.len(), 1);
+        assert_eq!(
+            service.unknown_chunks[0].header.chunk_id.ty,
+            SqttFileChunkType::CpuInfo as u8
+        );
+        assert_eq!(service.unknown_chunks[0].data, b"GenuineIntel");
+
+        // We can't tell where the strings are, so the chunk is dropped rather than kept unscrubbed.
+        let (_, chunks) = validate(&anonymize(&data).unwrap()).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks
+            .iter()
+            .all(|(_, entry)| entry.chunk_id.ty != SqttFileChunkType::CpuInfo as u8));
+
+        let data = capture_with_newer_cpu_info(1);
+        assert!(matches!(
+            validate(&data),
+            Err(RgpError::UnsupportedChunkVersion {
+                ty: SqttFileChunkType::AsicInfo,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn spm_samples_are_trimmed() {
+        let timestamps = [100u64, 200, 300];
+        let values = [[1u16, 2, 3], [4, 5, 6]];
+        let info_size = RgpSpmCounterInfo::size_with(&LE);
+        let preamble_size = ENTRY_HEADER_SIZE + RgpSpmDb::size_with(&LE);
+        let mut payload = vec![];
+        let header = RgpSpmDb {
+            flags: 0,
+            preamble_size: preamble_size as u32,
+            num_timestamps: timestamps.len() as u32,
+            num_spm_counter_info: values.len() as u32,
+            spm_counter_info_size: info_size as u32,
+            sample_interval: 100,
+        };
+        push_struct(&mut payload, header).unwrap();
+        for timestamp in timestamps {
+            push_struct(&mut payload, timestamp).unwrap();
+        }
+        let values_offset = preamble_size + timestamps.len() * 8 + values.len() * info_size;
+        for index in 0..values.len() {
+            let info = RgpSpmCounterInfo {
+                block: 0,
+                instance: 0,
+                data_offset: (values_offset + index * timestamps.len() * 2) as u32,
+                event_index: index as u32,
+            };
+            push_struct(&mut payload, info).unwrap();
+        }
+        for value in values.iter().flatten() {
+            push_struct(&mut payload, *value).unwrap();
+        }
+
+        let trace = parse_spm_db(&trim_spm_db(&payload, 150, 300).unwrap()).unwrap();
+        assert_eq!(trace.header.num_timestamps, 2);
+        assert_eq!(trace.timestamps, [200, 300]);
+        assert_eq!(trace.counters.len(), 2);
+        assert_eq!(trace.counters[0].values, [2, 3]);
+        assert_eq!(trace.counters[1].values, [5, 6]);
+        assert_eq!(trace.counters[1].info.event_index, 1);
+    }
+
+    #[test]
+    fn queue_events_are_trimmed() {
+        let event = |gpu_timestamps| RgpQueueEventRecord {
+            event_type: SqttQueueEventType::CmdbufSubmit.into(),
+            sqtt_cb_id: 0,
+            frame_index: 0,
+            queue_info_index: 0,
+            submit_sub_index: 0,
+            api_id: 0,
+            cpu_timestamp: 0,
+            gpu_timestamps,
+        };
+        let events = [[50, 100], [90, 160], [150, 250], [300, 0], [0, 0]].map(event);
+        let mut payload = vec![];
+        let header = RgpQueueEventTimings {
+            queue_info_table_record_count: 1,
+            queue_info_table_size: RgpQueueInfoRecord::size_with(&LE) as u32,
+            queue_event_table_record_count: events.len() as u32,
+            queue_event_table_size: (events.len() * RgpQueueEventRecord::size_with(&LE)) as u32,
+        };
+        push_struct(&mut payload, header).unwrap();
+        push_struct(&mut payload, zeroed::<RgpQueueInfoRecord>()).unwrap();
+        for event in events {
+            push_struct(&mut payload, event).unwrap();
+        }
+
+        let (queues, events) =
+            parse_queue_event_timings(&trim_queue_event_timings(&payload, 150, 250).unwrap()).unwrap();
+        assert_eq!(queues.len(), 1);
+        let kept: Vec<_> = events.iter().map(|event| event.gpu_timestamps).collect();
+        assert_eq!(kept, [[90, 160], [150, 250], [0, 0]]);
+    }
+
+    #[test]
+    fn user_markers_are_scrubbed() {
+        let mut header: RgpHeader = zeroed();
+        header.magic_number = RGP_MAGIC_NUMBER;
+        header.version_major = RGP_VERSION_MAJOR;
+        let mut writer = RgpWriter::new(&header).unwrap();
+        for profiling_mode in [SqttProfilingMode::UserMarkers, SqttProfilingMode::Index] {
+            let mut info: RgpApiInfo = zeroed();
+            info.profiling_mode = profiling_mode.into();
+            info.profiling_mode_data[..5].copy_from_slice(b"Frame");
+            let mut payload = vec![];
+            push_struct(&mut payload, info).unwrap();
+            writer.add_chunk(&entry(SqttFileChunkType::ApiInfo), &payload).unwrap();
+        }
+
+        let data = anonymize(&writer.finish()).unwrap();
+        let (_, chunks) = validate(&data).unwrap();
+        let infos: Vec<RgpApiInfo> = chunks
+            .iter()
+            .map(|(offset, _)| data.pread_with(offset + ENTRY_HEADER_SIZE, LE).unwrap())
+            .collect();
+        assert_eq!(infos[0].profiling_mode_data, [0; 512]);
+        assert_eq!(&infos[1].profiling_mode_data[..5], b"Frame");
+    }
+}